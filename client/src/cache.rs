@@ -0,0 +1,184 @@
+//! Last signed response of the server, trusted for a grace period while it can't be reached
+
+use std::{path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use prost::Message;
+use proto::{
+    software::v1::{
+        info_response, server_message, InfoResponse, ServerHearthbeat, ServerMessage,
+        SignatureSchema, VerifyingKey,
+    },
+    ChronoExt,
+};
+
+use crate::{license::License, DataVerifier};
+
+/// Kept next to the license key, so apps sharing a working directory don't mix up their caches
+fn cache_path(verifying_key: &VerifyingKey) -> PathBuf {
+    crate::app_config_dir(verifying_key).join("license.cache")
+}
+
+/// Remembers a handshake or heartbeat response that passed verification
+pub fn store(verifying_key: &VerifyingKey, response: server_message::Data) {
+    let path = cache_path(verifying_key);
+    let message = ServerMessage {
+        data: Some(response),
+    };
+    // losing the cache only costs the grace period, not worth failing over
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(path, message.encode_to_vec());
+}
+
+/// The license a cached response vouches for, if its signature holds
+fn verified_license(
+    message: ServerMessage,
+    verifying_key: &VerifyingKey,
+) -> Option<info_response::Response> {
+    match message.data? {
+        server_message::Data::Auth(InfoResponse {
+            nonce,
+            signature,
+            result: Some(info_response::Result::Ok(info)),
+        }) => SignatureSchema::verify(&info, nonce, verifying_key, &signature).then_some(info),
+        server_message::Data::Heathbeat(ServerHearthbeat {
+            nonce,
+            signature,
+            data: Some(data),
+        }) => {
+            if data.error.is_some()
+                || !SignatureSchema::verify(&data, nonce, verifying_key, &signature)
+            {
+                return None;
+            }
+            data.license
+        }
+        _ => None,
+    }
+}
+
+/// Loads the cached response if it is still trusted, together with the time
+/// its grace period ends
+pub fn load<V: DataVerifier>(
+    verifying_key: &VerifyingKey,
+    verifier: &V,
+    grace_period: Duration,
+) -> Option<(info_response::Response, License<V::Data>, DateTime<Utc>)> {
+    let file = std::fs::read(cache_path(verifying_key)).ok()?;
+    let message = ServerMessage::decode(file.as_slice()).ok()?;
+    let info = verified_license(message, verifying_key)?;
+
+    let issued_at = DateTime::try_from_protobuf(info.issued_at.as_ref()?)?;
+    let expiry = DateTime::try_from_protobuf(info.expiry.as_ref()?)?;
+    let grace_end = (issued_at + grace_period).min(expiry);
+    if Utc::now() > grace_end {
+        return None;
+    }
+
+    let license = License::verify(&info, verifier).ok()?;
+    Some((info, license, grace_end))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use proto::software::v1::{ServerHearthbeatData, SigningKey};
+
+    use super::*;
+
+    const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+    fn signing_key() -> SigningKey {
+        SigningKey::try_from(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    fn info(expires_in: TimeDelta) -> info_response::Response {
+        info_response::Response {
+            expiry: Some((Utc::now() + expires_in).to_protobuf()),
+            extra_data: "{}".to_owned(),
+            issued_at: Some(Utc::now().to_protobuf()),
+            holder: "holder".to_owned(),
+            resumption_token: Vec::new(),
+        }
+    }
+
+    fn auth(info: info_response::Response, signer: &mut SigningKey) -> server_message::Data {
+        server_message::Data::Auth(InfoResponse {
+            nonce: 7,
+            signature: SignatureSchema::sign(&info, 7, signer),
+            result: Some(info_response::Result::Ok(info)),
+        })
+    }
+
+    /// Runs `test` against a cache of its own, removed afterwards
+    fn with_app(test: impl FnOnce(&mut SigningKey, &VerifyingKey)) {
+        let mut signer = signing_key();
+        let key = VerifyingKey(signer.verifying_key());
+        test(&mut signer, &key);
+        let _ = std::fs::remove_dir_all(crate::app_config_dir(&key));
+    }
+
+    #[test]
+    fn loads_cached_handshake() {
+        with_app(|signer, key| {
+            assert!(load(key, &(), GRACE_PERIOD).is_none());
+
+            store(key, auth(info(TimeDelta::days(30)), signer));
+            let (info, license, grace_end) = load(key, &(), GRACE_PERIOD).unwrap();
+            assert_eq!(info.holder, "holder");
+            assert_eq!(license.holder, "holder");
+            let issued_at = DateTime::from_protobuf(info.issued_at.as_ref().unwrap());
+            assert_eq!(grace_end, issued_at + GRACE_PERIOD);
+        });
+    }
+
+    #[test]
+    fn loads_cached_heartbeat() {
+        with_app(|signer, key| {
+            let data = ServerHearthbeatData {
+                error: None,
+                license: Some(info(TimeDelta::days(30))),
+            };
+            store(
+                key,
+                server_message::Data::Heathbeat(ServerHearthbeat {
+                    nonce: 7,
+                    signature: SignatureSchema::sign(&data, 7, signer),
+                    data: Some(data),
+                }),
+            );
+            assert!(load(key, &(), GRACE_PERIOD).is_some());
+        });
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        with_app(|_, key| {
+            store(key, auth(info(TimeDelta::days(30)), &mut signing_key()));
+            assert!(load(key, &(), GRACE_PERIOD).is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_other_app() {
+        with_app(|signer, key| {
+            store(key, auth(info(TimeDelta::days(30)), signer));
+            with_app(|_, other| assert!(load(other, &(), GRACE_PERIOD).is_none()));
+        });
+    }
+
+    #[test]
+    fn grace_period_ends_at_expiry() {
+        with_app(|signer, key| {
+            store(key, auth(info(TimeDelta::minutes(10)), signer));
+            let (cached, _, grace_end) = load(key, &(), GRACE_PERIOD).unwrap();
+            let expiry = DateTime::from_protobuf(cached.expiry.as_ref().unwrap());
+            assert_eq!(grace_end, expiry);
+
+            store(key, auth(info(-TimeDelta::minutes(1)), signer));
+            assert!(load(key, &(), GRACE_PERIOD).is_none());
+        });
+    }
+}
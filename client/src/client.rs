@@ -1,9 +1,32 @@
-use crate::gui::{Dispatcher, GUIBackend};
+use crate::gui::GUIBackend;
 use connection::ConnectionError;
 use std::sync::Arc;
 
+/// What happens once the license check gives up
+#[derive(Clone, Default)]
+pub enum FailurePolicy {
+    /// Show the error and exit the process. Debug builds hand the error back instead.
+    #[default]
+    Exit,
+    /// Hand the error to the app, see [`ConnectionError::kind`]
+    Callback(Arc<dyn Fn(&ConnectionError) + Send + Sync>),
+    /// Show the error and keep running. The app reads the license status and
+    /// restricts itself.
+    ReadOnly,
+    /// Keep reconnecting with backoff until the server accepts the license again.
+    /// The status reports the failure meanwhile.
+    Retry,
+}
+
+impl FailurePolicy {
+    pub fn callback(callback: impl Fn(&ConnectionError) + Send + Sync + 'static) -> Self {
+        Self::Callback(Arc::new(callback))
+    }
+}
+
 pub struct ErrorDispatcher {
-    pub gui: Arc<Dispatcher>,
+    pub gui: Arc<dyn GUIBackend>,
+    pub policy: FailurePolicy,
 }
 
 impl ErrorDispatcher {
@@ -14,8 +37,8 @@ impl ErrorDispatcher {
         }
     }
 
-    #[allow(unused)]
-    fn handle_release(&self, error: ConnectionError) -> Result<(), ConnectionError> {
+    #[allow(unused, clippy::never_loop)]
+    fn handle_release(&self, error: ConnectionError) -> ! {
         if let ConnectionError::LicenseError(licerror) = error {
             self.gui.show_license_error(licerror);
         }
@@ -25,12 +48,138 @@ impl ErrorDispatcher {
         }
     }
 
-    pub fn dispatch(&self, error: ConnectionError) -> Result<(), ConnectionError> {
-        #[cfg(debug_assertions)]
-        return Err(error);
-        #[cfg(not(debug_assertions))]
-        return self.handle_release(error);
+    /// Applies the failure policy, handing the error back unless the process exits
+    pub fn dispatch(&self, error: ConnectionError) -> ConnectionError {
+        match &self.policy {
+            FailurePolicy::Exit => {
+                #[cfg(debug_assertions)]
+                return error;
+                #[cfg(not(debug_assertions))]
+                self.handle_release(error)
+            }
+            FailurePolicy::Callback(callback) => {
+                callback(&error);
+                error
+            }
+            FailurePolicy::ReadOnly => {
+                if let ConnectionError::LicenseError(licerror) = error {
+                    self.gui.show_license_error(licerror);
+                }
+                error
+            }
+            FailurePolicy::Retry => error,
+        }
+    }
+
+    /// Whether the license check goes on after [`ErrorDispatcher::dispatch`]
+    pub fn keeps_retrying(&self) -> bool {
+        matches!(self.policy, FailurePolicy::Retry)
     }
 }
 
 pub mod connection;
+pub mod supervisor;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use proto::software::v1::{info_response, LicenseError};
+
+    use super::*;
+    use connection::FailureKind;
+
+    /// Remembers the errors it was asked to show
+    #[derive(Default)]
+    struct Recorder {
+        shown: Mutex<Vec<LicenseError>>,
+    }
+
+    impl GUIBackend for Recorder {
+        fn prompt_license(&self) -> String {
+            String::new()
+        }
+
+        fn show_license_details(&self, _: info_response::Response) {}
+
+        fn show_license_error(&self, error: LicenseError) {
+            self.shown.lock().unwrap().push(error);
+        }
+    }
+
+    fn dispatcher(policy: FailurePolicy) -> (ErrorDispatcher, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let dispatcher = ErrorDispatcher {
+            gui: recorder.clone(),
+            policy,
+        };
+        (dispatcher, recorder)
+    }
+
+    fn revoked() -> ConnectionError {
+        ConnectionError::LicenseError(LicenseError::Revoked)
+    }
+
+    fn unreachable() -> ConnectionError {
+        ConnectionError::from_call(tonic::Status::unavailable("down"))
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn exit_hands_errors_back_in_debug_builds() {
+        let (dispatcher, recorder) = dispatcher(FailurePolicy::Exit);
+        let error = dispatcher.dispatch(revoked());
+        assert_eq!(error.kind(), FailureKind::License(LicenseError::Revoked));
+        assert!(recorder.shown.lock().unwrap().is_empty());
+        assert!(!dispatcher.keeps_retrying());
+    }
+
+    #[test]
+    fn callback_sees_every_kind() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let policy = FailurePolicy::callback({
+            let seen = seen.clone();
+            move |error| seen.lock().unwrap().push(error.kind())
+        });
+        let (dispatcher, recorder) = dispatcher(policy);
+
+        dispatcher.dispatch(revoked());
+        dispatcher.dispatch(unreachable());
+        dispatcher.dispatch(ConnectionError::InvalidSignature);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                FailureKind::License(LicenseError::Revoked),
+                FailureKind::Transport,
+                FailureKind::Other,
+            ]
+        );
+        assert!(recorder.shown.lock().unwrap().is_empty());
+        assert!(!dispatcher.keeps_retrying());
+    }
+
+    #[test]
+    fn read_only_shows_license_errors() {
+        let (dispatcher, recorder) = dispatcher(FailurePolicy::ReadOnly);
+
+        let error = dispatcher.dispatch(revoked());
+        assert_eq!(error.kind(), FailureKind::License(LicenseError::Revoked));
+        // there is nothing to tell the user about a transport error
+        let error = dispatcher.dispatch(unreachable());
+        assert_eq!(error.kind(), FailureKind::Transport);
+
+        assert_eq!(*recorder.shown.lock().unwrap(), [LicenseError::Revoked]);
+        assert!(!dispatcher.keeps_retrying());
+    }
+
+    #[test]
+    fn retry_keeps_going_quietly() {
+        let (dispatcher, recorder) = dispatcher(FailurePolicy::Retry);
+
+        let error = dispatcher.dispatch(revoked());
+        assert_eq!(error.kind(), FailureKind::License(LicenseError::Revoked));
+        assert!(recorder.shown.lock().unwrap().is_empty());
+        assert!(dispatcher.keeps_retrying());
+    }
+}
@@ -1,39 +1,52 @@
 use std::convert::Infallible;
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use fatality::fatality;
 use proto::software::v1::VerifyingKey;
 use proto::software::v1::{
     self, authority_client::AuthorityClient, client_message, info_request, server_message,
-    ClientHearthbeat, ClientMessage, InfoRequest, LicenseError, ServerHearthbeat, ServerMessage,
+    ClientGoodbye, ClientHearthbeat, ClientMessage, InfoRequest, LicenseError, ServerGoodbye,
+    ServerHearthbeat, ServerMessage,
 };
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use tokio::sync::mpsc::Sender;
 use tonic::{transport::Channel, Streaming};
 
-use crate::DataVerifier;
+use crate::{
+    license::{License, LicenseSender},
+    DataVerifier,
+};
 
-use crate::gui::{Dispatcher, GUIBackend};
+use crate::gui::GUIBackend;
 
 #[fatality]
 pub enum ConnectionError {
+    #[fatal]
     #[error("License error")]
     LicenseError(v1::LicenseError),
 
     #[fatal]
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[fatal]
+    #[error("Invalid verifying key")]
+    BadVerifyingKey,
+
+    // transport errors aren't fatal, reconnecting may fix them
+    #[error("Server unreachable: {0}")]
+    Unreachable(tonic::Status),
+
     #[error("Connection error: {0}")]
     ConnectionError(#[from] tonic::Status),
 
-    #[fatal]
     #[error("Send error: {0}")]
     SendError(#[from] tokio::sync::mpsc::error::SendError<ClientMessage>),
 
-    #[fatal]
     #[error("Timeout")]
     Timeout(#[from] tokio::time::error::Elapsed),
 
-    #[fatal]
     #[error("Invalid response")]
     InvalidResponse,
 
@@ -44,17 +57,94 @@ pub enum ConnectionError {
     #[fatal]
     #[error("Invalid signature")]
     InvalidSignature,
+
+    #[fatal]
+    #[error("Invalid offline license")]
+    InvalidOfflineLicense,
+
+    #[fatal]
+    #[error("Machine could not be identified")]
+    UnknownMachine,
+
+    #[fatal]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What went wrong, as far as a [`crate::client::FailurePolicy`] is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// the server refused the license
+    License(LicenseError),
+    /// the server couldn't be reached or the connection broke, see [`ConnectionError::is_transient`]
+    Transport,
+    /// configuration, verification or local failures
+    Other,
+}
+
+impl ConnectionError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            ConnectionError::LicenseError(error) => FailureKind::License(*error),
+            error if error.is_transient() => FailureKind::Transport,
+            _ => FailureKind::Other,
+        }
+    }
+
+    /// Tells a server that can't be reached apart from other call failures
+    pub fn from_call(status: tonic::Status) -> Self {
+        match status.code() {
+            tonic::Code::Unavailable => ConnectionError::Unreachable(status),
+            _ => ConnectionError::ConnectionError(status),
+        }
+    }
+
+    /// Whether the error comes from the network rather than from the license itself,
+    /// so reconnecting may fix it
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::Unreachable(_)
+                | ConnectionError::ConnectionError(_)
+                | ConnectionError::SendError(_)
+                | ConnectionError::Timeout(_)
+                | ConnectionError::InvalidResponse
+        )
+    }
 }
 
 pub struct ConnectionState<D: DataVerifier> {
     pub client: AuthorityClient<Channel>,
     pub rng: rand::rngs::StdRng,
     pub verification_key: VerifyingKey,
-    pub gui: Arc<Dispatcher>,
+    pub gui: Arc<dyn GUIBackend>,
 
     pub license_key: String,
+    /// see [`crate::fingerprint`], empty if the machine could not be identified
+    pub machine_id: String,
 
-    pub data_verifier: D,
+    pub data_verifier: Arc<D>,
+    /// receives every license the server sends
+    pub license: Arc<LicenseSender<D::Data>>,
+    /// from the last accepted handshake or heartbeat, lets a reconnect take over that session's seat
+    pub resumption_token: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<D: DataVerifier> Clone for ConnectionState<D> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            // a copied rng would repeat the nonces of the original
+            rng: rand::rngs::StdRng::from_os_rng(),
+            verification_key: self.verification_key.clone(),
+            gui: self.gui.clone(),
+            license_key: self.license_key.clone(),
+            machine_id: self.machine_id.clone(),
+            data_verifier: self.data_verifier.clone(),
+            license: self.license.clone(),
+            resumption_token: self.resumption_token.clone(),
+        }
+    }
 }
 
 pub struct Authorized;
@@ -64,6 +154,8 @@ pub struct Connection<D: DataVerifier, State> {
     pub state: ConnectionState<D>,
     tx: Sender<ClientMessage>,
     rx: Streaming<ServerMessage>,
+    /// license details of the handshake, empty until authorized
+    details: v1::info_response::Response,
     _s: PhantomData<State>,
 }
 
@@ -73,13 +165,15 @@ impl<D: DataVerifier> Connection<D, NotAuthorized> {
         let rx = state
             .client
             .hearthbeat(tokio_stream::wrappers::ReceiverStream::new(client_rx))
-            .await?
+            .await
+            .map_err(ConnectionError::from_call)?
             .into_inner();
 
         Ok(Self {
             state,
             tx,
             rx,
+            details: Default::default(),
             _s: Default::default(),
         })
     }
@@ -87,6 +181,9 @@ impl<D: DataVerifier> Connection<D, NotAuthorized> {
     pub async fn authorize(mut self) -> Result<Connection<D, Authorized>, ConnectionError> {
         let info_request = info_request::Request {
             key_id: self.state.license_key.trim().to_owned(),
+            machine_id: self.state.machine_id.clone(),
+            resumption_token: self.state.resumption_token.lock().unwrap().clone(),
+            app: self.state.verification_key.0.to_bytes().to_vec(),
         };
 
         let auth_nonce = self.state.rng.random();
@@ -111,6 +208,12 @@ impl<D: DataVerifier> Connection<D, NotAuthorized> {
             return Err(ConnectionError::InvalidResponse);
         };
 
+        // a signed answer to another request must not pass for this one
+        if response.nonce != auth_nonce {
+            return Err(ConnectionError::InvalidSignature);
+        }
+        let signed_error = response.is_signed_error(&self.state.verification_key);
+
         let v1::InfoResponse {
             nonce,
             signature,
@@ -131,17 +234,26 @@ impl<D: DataVerifier> Connection<D, NotAuthorized> {
                     return Err(ConnectionError::InvalidSignature);
                 }
 
-                let extra_data: serde_json::Value = serde_json::from_str(&info.extra_data)
-                    .map_err(|_| ConnectionError::DataVerificationError)?;
-                self.state
-                    .data_verifier
-                    .verify(extra_data)
-                    .then(|| ())
-                    .ok_or(ConnectionError::DataVerificationError)?;
+                let license = License::verify(&info, &*self.state.data_verifier)?;
+                *self.state.resumption_token.lock().unwrap() = info.resumption_token.clone();
 
-                self.state.gui.show_license_details(info);
+                crate::cache::store(
+                    &self.state.verification_key,
+                    server_message::Data::Auth(v1::InfoResponse {
+                        nonce,
+                        signature,
+                        result: Some(v1::info_response::Result::Ok(info.clone())),
+                    }),
+                );
+
+                self.state.license.publish(license);
+                self.details = info;
             }
             v1::info_response::Result::Error(e) => {
+                // anyone on the way could claim the key is invalid, only the server can sign it
+                if !signed_error {
+                    return Err(ConnectionError::InvalidSignature);
+                }
                 return Err(ConnectionError::LicenseError(
                     LicenseError::try_from(e).unwrap_or(LicenseError::Internal),
                 ));
@@ -152,13 +264,21 @@ impl<D: DataVerifier> Connection<D, NotAuthorized> {
             state: self.state,
             tx: self.tx,
             rx: self.rx,
+            details: self.details,
             _s: Default::default(),
         })
     }
 }
 
 impl<D: DataVerifier> Connection<D, Authorized> {
-    pub async fn work(mut self) -> Result<Infallible, ConnectionError> {
+    /// License details the server granted the session with
+    pub fn details(&self) -> &v1::info_response::Response {
+        &self.details
+    }
+
+    /// Keeps the session alive until it fails. Cancelling it leaves the connection
+    /// usable for [`Connection::close`].
+    pub async fn work(&mut self) -> Result<Infallible, ConnectionError> {
         loop {
             tokio::time::sleep(v1::PING_PERIOD).await;
             let nonce = self.state.rng.random();
@@ -177,7 +297,7 @@ impl<D: DataVerifier> Connection<D, Authorized> {
             let ServerMessage {
                 data:
                     Some(server_message::Data::Heathbeat(ServerHearthbeat {
-                        nonce,
+                        nonce: response_nonce,
                         signature,
                         data: Some(data),
                     })),
@@ -186,7 +306,13 @@ impl<D: DataVerifier> Connection<D, Authorized> {
                 return Err(ConnectionError::InvalidResponse);
             };
 
-            if !v1::SignatureSchema::verify(&data, nonce, &self.state.verification_key, &signature)
+            if response_nonce != nonce
+                || !v1::SignatureSchema::verify(
+                    &data,
+                    nonce,
+                    &self.state.verification_key,
+                    &signature,
+                )
             {
                 return Err(ConnectionError::InvalidSignature);
             }
@@ -196,6 +322,115 @@ impl<D: DataVerifier> Connection<D, Authorized> {
                     LicenseError::try_from(error).unwrap_or(LicenseError::Internal),
                 ));
             }
+
+            if let Some(info) = &data.license {
+                let license = License::verify(info, &*self.state.data_verifier)?;
+                if !info.resumption_token.is_empty() {
+                    *self.state.resumption_token.lock().unwrap() = info.resumption_token.clone();
+                }
+                self.state.license.publish(license);
+                // restarts the grace period from this check
+                crate::cache::store(
+                    &self.state.verification_key,
+                    server_message::Data::Heathbeat(ServerHearthbeat {
+                        nonce,
+                        signature,
+                        data: Some(data),
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Ends the session and gives its seat back right away, instead of leaving it taken
+    /// until the server times it out. Returns whether the server released a seat.
+    pub async fn close(mut self) -> Result<bool, ConnectionError> {
+        let nonce = self.state.rng.random();
+        self.tx
+            .send(ClientMessage {
+                data: Some(client_message::Data::Goodbye(ClientGoodbye { nonce })),
+            })
+            .await?;
+
+        tokio::time::timeout(v1::HANDSHAKE_TIMEOUT, async {
+            loop {
+                let Some(msg) = self.rx.message().await? else {
+                    return Err(ConnectionError::InvalidResponse);
+                };
+                // a heartbeat answer may still be on its way if `work` was cancelled
+                let ServerMessage {
+                    data:
+                        Some(server_message::Data::Goodbye(ServerGoodbye {
+                            nonce: response_nonce,
+                            signature,
+                            data: Some(data),
+                        })),
+                } = msg
+                else {
+                    continue;
+                };
+
+                if response_nonce != nonce
+                    || !v1::SignatureSchema::verify(
+                        &data,
+                        nonce,
+                        &self.state.verification_key,
+                        &signature,
+                    )
+                {
+                    return Err(ConnectionError::InvalidSignature);
+                }
+                return Ok(data.released);
+            }
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_errors_are_transient() {
+        let unreachable = ConnectionError::from_call(tonic::Status::unavailable("down"));
+        assert!(matches!(unreachable, ConnectionError::Unreachable(_)));
+        let failed = ConnectionError::from_call(tonic::Status::internal("broken"));
+        assert!(matches!(failed, ConnectionError::ConnectionError(_)));
+
+        for error in [unreachable, failed, ConnectionError::InvalidResponse] {
+            assert!(error.is_transient());
+            assert_eq!(error.kind(), FailureKind::Transport);
+        }
+    }
+
+    #[test]
+    fn license_errors_are_not_transient() {
+        for license_error in [
+            LicenseError::Expired,
+            LicenseError::Revoked,
+            LicenseError::TooManySessions,
+            LicenseError::SessionTerminated,
+        ] {
+            let error = ConnectionError::LicenseError(license_error);
+            assert!(!error.is_transient());
+            assert_eq!(error.kind(), FailureKind::License(license_error));
+        }
+    }
+
+    #[test]
+    fn local_errors_are_other() {
+        for error in [
+            ConnectionError::InvalidConfig("addr".to_owned()),
+            ConnectionError::BadVerifyingKey,
+            ConnectionError::DataVerificationError,
+            ConnectionError::InvalidSignature,
+            ConnectionError::InvalidOfflineLicense,
+            ConnectionError::UnknownMachine,
+            ConnectionError::Io(std::io::ErrorKind::NotFound.into()),
+        ] {
+            assert!(!error.is_transient());
+            assert_eq!(error.kind(), FailureKind::Other);
         }
     }
 }
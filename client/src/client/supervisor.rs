@@ -0,0 +1,204 @@
+//! Keeps the license check running across network failures
+
+use std::{future::Future, time::Duration};
+
+use chrono::Utc;
+use proto::software::v1::LicenseError;
+use tokio::time::Instant;
+
+use super::{
+    connection::{Authorized, Connection, ConnectionError, ConnectionState},
+    ErrorDispatcher,
+};
+use crate::{license::LicenseStatus, DataVerifier};
+
+/// How long `Connector::setup` keeps retrying before falling back to the cached response
+pub const STARTUP_RETRY_WINDOW: Duration = Duration::from_secs(15);
+
+/// Exponential backoff with jitter, so clients cut off together don't reconnect in lockstep
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        Self {
+            next: Self::INITIAL,
+        }
+    }
+
+    /// Somewhere between half and all of the current step
+    fn next_delay(&mut self) -> Duration {
+        let step = self.next;
+        self.next = (step * 2).min(Self::MAX);
+        step / 2 + step.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+pub struct Supervisor<D: DataVerifier> {
+    pub state: ConnectionState<D>,
+    /// how long the last verified response is trusted while the server can't be reached
+    pub grace_period: Duration,
+    pub err_dispatcher: ErrorDispatcher,
+}
+
+impl<D: DataVerifier> Supervisor<D> {
+    /// Opens a connection and authorizes it with the stored key
+    pub async fn connect(&self) -> Result<Connection<D, Authorized>, ConnectionError> {
+        Connection::new(self.state.clone()).await?.authorize().await
+    }
+
+    /// Retries [`Supervisor::connect`] with backoff while `retry` accepts the error and
+    /// the next attempt would start before `deadline`
+    pub async fn connect_until(
+        &self,
+        deadline: Instant,
+        retry: impl Fn(&ConnectionError) -> bool,
+    ) -> Result<Connection<D, Authorized>, ConnectionError> {
+        let mut backoff = Backoff::new();
+        loop {
+            let err = match self.connect().await {
+                Ok(connection) => return Ok(connection),
+                Err(err) => err,
+            };
+
+            let delay = backoff.next_delay();
+            if !retry(&err) || Instant::now() + delay >= deadline {
+                return Err(err);
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Errors worth another attempt while reconnecting
+    pub fn is_retriable(error: &ConnectionError) -> bool {
+        // the seat of the dropped connection is held until the server notices it's gone
+        error.is_transient()
+            || matches!(
+                error,
+                ConnectionError::LicenseError(LicenseError::TooManySessions)
+            )
+    }
+
+    /// Reports the failure, returns whether the failure policy asks to reconnect
+    fn escalate(&self, error: ConnectionError) -> bool {
+        self.state
+            .license
+            .set_status(LicenseStatus::from_error(&error));
+        // debug builds keep running, the status tells the app what happened
+        let _ = self.err_dispatcher.dispatch(error);
+        self.err_dispatcher.keeps_retrying()
+    }
+
+    /// Reconnects with backoff, however long it takes the server to accept the license again
+    async fn reconnect(&self) -> Connection<D, Authorized> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.connect().await {
+                Ok(connection) => return connection,
+                // an unreachable server doesn't change what is known about the license
+                Err(err) if !err.is_transient() => self
+                    .state
+                    .license
+                    .set_status(LicenseStatus::from_error(&err)),
+                Err(_) => {}
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+    }
+
+    /// Runs `connection`, or reconnects until `grace_end` if there is none yet.
+    /// Only license and verification failures, or running out of grace, are escalated.
+    /// Stops, closing the session, when the app asks for it through its handle.
+    pub async fn run(self, mut connection: Option<Connection<D, Authorized>>, grace_end: Instant) {
+        let mut grace_end = grace_end;
+        loop {
+            if let Some(mut current) = connection.take() {
+                let Err(err) = tokio::select! {
+                    result = current.work() => result,
+                    _ = self.state.license.close_requested() => {
+                        let _ = current.close().await;
+                        return;
+                    }
+                };
+                if !err.is_transient() {
+                    connection = self.recover(err).await;
+                    if connection.is_none() {
+                        return;
+                    }
+                    continue;
+                }
+                // the last heartbeat was verified at most one ping period ago,
+                // but it doesn't vouch for the license past its expiry
+                let mut until = Utc::now() + self.grace_period;
+                if let Some(license) = self.state.license.current() {
+                    until = until.min(license.expiry);
+                }
+                grace_end = Instant::now() + (until - Utc::now()).to_std().unwrap_or_default();
+                self.state
+                    .license
+                    .set_status(LicenseStatus::OfflineGrace { until });
+            }
+
+            connection = match self
+                .until_closed(self.connect_until(grace_end, Self::is_retriable))
+                .await
+            {
+                Some(Ok(reconnected)) => Some(reconnected),
+                Some(Err(err)) => self.recover(err).await,
+                None => None,
+            };
+            if connection.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Escalates `error`, then reconnects if the failure policy asks for it
+    pub async fn recover(&self, error: ConnectionError) -> Option<Connection<D, Authorized>> {
+        if !self.escalate(error) {
+            return None;
+        }
+        self.until_closed(self.reconnect()).await
+    }
+
+    /// `None` if the app closed the handle first. A connection opened meanwhile is
+    /// dropped, which ends its stream.
+    async fn until_closed<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            result = future => Some(result),
+            _ = self.state.license.close_requested() => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        let mut backoff = Backoff::new();
+        let mut step = Backoff::INITIAL;
+        for _ in 0..20 {
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= step / 2 && delay <= step,
+                "{delay:?} outside {step:?}"
+            );
+            step = (step * 2).min(Backoff::MAX);
+        }
+        assert_eq!(backoff.next, Backoff::MAX);
+    }
+
+    #[test]
+    fn backoff_jitter_spreads_delays() {
+        let delays: Vec<_> = (0..32).map(|_| Backoff::new().next_delay()).collect();
+        assert!(delays.iter().all(|delay| *delay >= Backoff::INITIAL / 2));
+        assert!(delays.iter().all(|delay| *delay <= Backoff::INITIAL));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+}
@@ -0,0 +1,39 @@
+//! Stable identifier of the machine the client runs on, used for node-locking.
+//!
+//! The raw identifiers never leave the machine: they are hashed together with
+//! the app's verifying key, so the same machine has unrelated ids across apps.
+
+use proto::software::v1::VerifyingKey;
+use sha2::{Digest, Sha256};
+
+/// Sources that survive reboots and package upgrades, in order of preference
+const SOURCES: &[&str] = &[
+    "/etc/machine-id",
+    "/var/lib/dbus/machine-id",
+    "/sys/class/dmi/id/product_uuid",
+];
+
+fn read_identifiers() -> Vec<String> {
+    SOURCES
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_owned())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Hashed fingerprint of this machine, or `None` if no identifier could be read
+pub fn machine_id(app: &VerifyingKey) -> Option<String> {
+    let identifiers = read_identifiers();
+    if identifiers.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(app.0.as_bytes());
+    for id in identifiers {
+        hasher.update(id.as_bytes());
+        hasher.update([0]);
+    }
+    Some(hex::encode(hasher.finalize()))
+}
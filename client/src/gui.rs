@@ -1,4 +1,11 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    cell::RefCell,
+    io::IsTerminal,
+    ops::Deref,
+    rc::Rc,
+    sync::{mpsc, Arc, OnceLock},
+    time::Instant,
+};
 
 use chrono::{DateTime, Utc};
 use colored::Colorize;
@@ -22,6 +29,9 @@ fn display_license_error(error: &LicenseError) -> &'static str {
         LicenseError::TooManySessions => "Too many sessions!",
         LicenseError::Revoked => "Your license has been revoked!",
         LicenseError::Internal => "Internal error! Contact support.",
+        LicenseError::SessionTerminated => "Your session has been terminated!",
+        LicenseError::MachineLimit => "This license is activated on too many machines!",
+        LicenseError::MachineUnknown => "This machine could not be identified!",
     }
 }
 
@@ -38,17 +48,16 @@ impl GUIBackend for TUI {
 
     fn show_license_details(&self, license: info_response::Response) {
         let expiration_line = format!(
-            "You license expires at: {}",
+            "Your license expires at: {}",
             license
                 .expiry
                 .map(|d| DateTime::from_protobuf(&d))
                 .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC)
-                .to_string()
         );
         println!("{}\n{}", "Access Granted!".green(), expiration_line);
     }
     fn show_license_error(&self, error: LicenseError) {
-        let error = println!(
+        println!(
             "{}\n{}",
             "Access Denied!".red(),
             display_license_error(&error)
@@ -57,32 +66,209 @@ impl GUIBackend for TUI {
     }
 }
 
+enum Request {
+    PromptLicense(mpsc::Sender<String>),
+    LicenseDetails(info_response::Response),
+    LicenseError(LicenseError, mpsc::Sender<()>),
+}
+
+/// Native windows drawn with egui.
+///
+/// winit allows a single event loop per process, so every window is opened from one
+/// UI thread that lives as long as the process and serves the requests in order.
 pub struct GUI;
 
+impl GUI {
+    const WINDOW_SIZE: [f32; 2] = [380.0, 150.0];
+
+    fn send(request: Request) {
+        static UI_THREAD: OnceLock<Option<mpsc::Sender<Request>>> = OnceLock::new();
+        let tx = UI_THREAD.get_or_init(|| {
+            let (tx, rx) = mpsc::channel();
+            let spawned = std::thread::Builder::new()
+                .name("license-gui".into())
+                .spawn(move || rx.into_iter().for_each(Self::serve));
+            match spawned {
+                Ok(_) => Some(tx),
+                Err(err) => {
+                    tracing::error!("gui.thread_failed: {err}");
+                    None
+                }
+            }
+        });
+        // a UI thread that never started or died serves nothing, the request still needs an answer
+        let unserved = match tx {
+            Some(tx) => tx
+                .send(request)
+                .err()
+                .map(|mpsc::SendError(request)| request),
+            None => Some(request),
+        };
+        if let Some(request) = unserved {
+            Self::serve_without_windows(request);
+        }
+    }
+
+    /// Answers a request from the terminal, or not at all without one
+    fn serve_without_windows(request: Request) {
+        let backend: &dyn GUIBackend = if std::io::stdin().is_terminal() {
+            &TUI
+        } else {
+            &Headless
+        };
+        match request {
+            Request::PromptLicense(reply) => {
+                let _ = reply.send(backend.prompt_license());
+            }
+            Request::LicenseDetails(license) => backend.show_license_details(license),
+            Request::LicenseError(error, reply) => {
+                backend.show_license_error(error);
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    fn serve(request: Request) {
+        match request {
+            Request::PromptLicense(reply) => {
+                let key = Rc::new(RefCell::new(String::new()));
+                let entered = key.clone();
+                Self::window("License activation", move |ui| {
+                    ui.label("Enter your license key");
+                    let input = ui.add(
+                        egui::TextEdit::singleline(&mut *entered.borrow_mut())
+                            .desired_width(f32::INFINITY),
+                    );
+                    let submitted =
+                        input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    submitted || ui.button("Activate").clicked()
+                });
+                let _ = reply.send(key.take().trim().to_owned());
+            }
+            Request::LicenseDetails(license) => {
+                let expiry = license
+                    .expiry
+                    .map(|d| DateTime::from_protobuf(&d))
+                    .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+                let shown = Instant::now();
+                Self::window("License", move |ui| {
+                    ui.heading("Access Granted!");
+                    if !license.holder.is_empty() {
+                        ui.label(format!("Licensed to: {}", license.holder));
+                    }
+                    ui.label(format!("Your license expires at: {expiry}"));
+                    ui.ctx().request_repaint_after(GUI_DELAY);
+                    ui.button("OK").clicked() || shown.elapsed() >= GUI_DELAY
+                });
+            }
+            Request::LicenseError(error, reply) => {
+                Self::window("License error", move |ui| {
+                    ui.heading("Access Denied!");
+                    ui.label(display_license_error(&error));
+                    ui.button("Close").clicked()
+                });
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    /// Shows a window until `content` returns `true` or the user closes it
+    fn window(title: &str, mut content: impl FnMut(&mut egui::Ui) -> bool + 'static) {
+        let mut options = eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default()
+                .with_inner_size(Self::WINDOW_SIZE)
+                .with_resizable(false),
+            centered: true,
+            ..Default::default()
+        };
+        options.event_loop_builder = Some(Box::new(|builder| {
+            // the UI thread is not the main thread
+            #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+            winit::platform::x11::EventLoopBuilderExtX11::with_any_thread(builder, true);
+            #[cfg(target_os = "windows")]
+            winit::platform::windows::EventLoopBuilderExtWindows::with_any_thread(builder, true);
+            #[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "windows")))]
+            let _ = builder;
+        }));
+
+        let result = eframe::run_simple_native(title, options, move |ctx, _| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                if content(ui) {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+            });
+        });
+        if let Err(err) = result {
+            tracing::error!("gui.window_failed: {err}");
+        }
+    }
+
+    /// Whether windows can be shown at all
+    fn available() -> bool {
+        if cfg!(any(target_os = "linux", target_os = "freebsd")) {
+            ["DISPLAY", "WAYLAND_DISPLAY"]
+                .iter()
+                .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+        } else {
+            // macOS only runs event loops on the main thread, which belongs to the app
+            cfg!(target_os = "windows")
+        }
+    }
+}
+
 impl GUIBackend for GUI {
     fn prompt_license(&self) -> String {
-        todo!()
+        let (tx, rx) = mpsc::channel();
+        Self::send(Request::PromptLicense(tx));
+        rx.recv().unwrap_or_default()
     }
 
     fn show_license_details(&self, license: info_response::Response) {
-        todo!()
+        // informational only, the app keeps starting while it's shown
+        Self::send(Request::LicenseDetails(license));
     }
 
     fn show_license_error(&self, error: LicenseError) {
-        todo!()
+        let (tx, rx) = mpsc::channel();
+        Self::send(Request::LicenseError(error, tx));
+        let _ = rx.recv();
+    }
+}
+
+/// For services and apps with their own UI: never asks or shows anything.
+/// Without a stored key the check fails with `InvalidKey`; the outcome is read from
+/// [`crate::license::LicenseHandle::status`] and the errors returned by `setup`.
+pub struct Headless;
+
+impl GUIBackend for Headless {
+    fn prompt_license(&self) -> String {
+        String::new()
     }
+
+    fn show_license_details(&self, _license: info_response::Response) {}
+
+    fn show_license_error(&self, _error: LicenseError) {}
 }
 
 pub struct Dispatcher {
     backend: Box<dyn GUIBackend>,
 }
 
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Dispatcher {
+    /// Windows when there is a display and nobody at a terminal, the terminal otherwise
     pub fn new() -> Self {
-        // TODO: add gui dispatching here
-        Self {
-            backend: Box::new(TUI),
-        }
+        let backend: Box<dyn GUIBackend> = if GUI::available() && !std::io::stdin().is_terminal() {
+            Box::new(GUI)
+        } else {
+            Box::new(TUI)
+        };
+        Self { backend }
     }
 }
 
@@ -100,7 +286,7 @@ impl GUIBackend for Dispatcher {
     }
 }
 
-impl<T: GUIBackend + Sync> GUIBackend for Arc<T> {
+impl<T: GUIBackend + Sync + ?Sized> GUIBackend for Arc<T> {
     fn prompt_license(&self) -> String {
         self.deref().prompt_license()
     }
@@ -0,0 +1,365 @@
+//! Where the user's license key is kept between runs
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+pub trait KeyStore: Send + Sync + 'static {
+    /// The stored key, `None` if there is none or it can't be read
+    fn load(&self) -> Option<String>;
+    /// Replaces the stored key
+    fn store(&self, key: &str) -> io::Result<()>;
+    /// Removes the stored key, so the user is asked again
+    fn forget(&self) -> io::Result<()>;
+}
+
+/// Where the key was kept before key stores, relative to the working directory
+const LEGACY_KEY_PATH: &str = "./license.data";
+
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // readable by the owner only from the start, not just once the key is in
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // a file that already existed keeps its mode
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Plaintext key in a file, by default under the user's config directory
+/// (`$XDG_CONFIG_HOME` on Linux)
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// `<config dir>/<app>/license.key`, falls back to the working directory
+    /// if there is no config directory
+    pub fn config_dir(app: &str) -> Self {
+        let dir = dirs::config_dir().unwrap_or_default();
+        Self::at(dir.join(app).join("license.key"))
+    }
+
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Moves the key older versions kept in `./license.data` into this store,
+    /// unless it has one already
+    pub fn migrate_legacy(&self) -> io::Result<()> {
+        self.migrate_from(Path::new(LEGACY_KEY_PATH))
+    }
+
+    fn migrate_from(&self, legacy: &Path) -> io::Result<()> {
+        if self.load().is_some() {
+            return Ok(());
+        }
+        let key = match std::fs::read_to_string(legacy) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        if !key.trim().is_empty() {
+            self.store(&key)?;
+        }
+        std::fs::remove_file(legacy)
+    }
+}
+
+impl KeyStore for FileStore {
+    fn load(&self) -> Option<String> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .map(|key| key.trim().to_owned())
+            .filter(|key| !key.is_empty())
+    }
+
+    fn store(&self, key: &str) -> io::Result<()> {
+        write_private(&self.path, key.trim().as_bytes())
+    }
+
+    fn forget(&self) -> io::Result<()> {
+        remove_if_exists(&self.path)
+    }
+}
+
+/// Key provided by the environment, e.g. on CI or in containers. It can't be changed
+/// from within the process.
+pub struct EnvStore {
+    var: String,
+}
+
+impl EnvStore {
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl KeyStore for EnvStore {
+    fn load(&self) -> Option<String> {
+        std::env::var(&self.var)
+            .ok()
+            .map(|key| key.trim().to_owned())
+            .filter(|key| !key.is_empty())
+    }
+
+    fn store(&self, _: &str) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("license key is read from ${}", self.var),
+        ))
+    }
+
+    fn forget(&self) -> io::Result<()> {
+        self.store("")
+    }
+}
+
+/// Key that only lives as long as the process, mostly for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    key: Mutex<Option<String>>,
+}
+
+impl MemoryStore {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: Mutex::new(Some(key.into())),
+        }
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn load(&self) -> Option<String> {
+        self.key.lock().unwrap().clone()
+    }
+
+    fn store(&self, key: &str) -> io::Result<()> {
+        *self.key.lock().unwrap() = Some(key.trim().to_owned());
+        Ok(())
+    }
+
+    fn forget(&self) -> io::Result<()> {
+        *self.key.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+/// Key in a file encrypted with ChaCha20-Poly1305. The cipher key is derived from
+/// `passphrase` with Argon2id and a random salt kept in the file; passing something
+/// machine specific such as [`crate::fingerprint::machine_id`] keeps the file useless elsewhere.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    passphrase: Vec<u8>,
+}
+
+impl EncryptedFileStore {
+    const SALT_LEN: usize = 16;
+    const NONCE_LEN: usize = 12;
+
+    pub fn new(path: impl Into<PathBuf>, passphrase: impl AsRef<[u8]>) -> Self {
+        Self {
+            path: path.into(),
+            passphrase: passphrase.as_ref().to_vec(),
+        }
+    }
+
+    fn cipher(&self, salt: &[u8]) -> io::Result<ChaCha20Poly1305> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(&self.passphrase, salt, &mut key)
+            .map_err(|_| io::Error::other("license key derivation failed"))?;
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+}
+
+impl KeyStore for EncryptedFileStore {
+    fn load(&self) -> Option<String> {
+        let file = std::fs::read(&self.path).ok()?;
+        if file.len() < Self::SALT_LEN + Self::NONCE_LEN {
+            return None;
+        }
+        let (salt, file) = file.split_at(Self::SALT_LEN);
+        let (nonce, ciphertext) = file.split_at(Self::NONCE_LEN);
+        let key = self
+            .cipher(salt)
+            .ok()?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        String::from_utf8(key).ok()
+    }
+
+    fn store(&self, key: &str) -> io::Result<()> {
+        let mut salt = [0; Self::SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(&nonce, key.trim().as_bytes())
+            .map_err(|_| io::Error::other("license key encryption failed"))?;
+
+        let mut file = salt.to_vec();
+        file.extend_from_slice(&nonce);
+        file.extend_from_slice(&ciphertext);
+        write_private(&self.path, &file)
+    }
+
+    fn forget(&self) -> io::Result<()> {
+        remove_if_exists(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("keystore-{}", rand::random::<u64>()))
+            .join(name)
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let path = temp_path("license.key");
+        let store = FileStore::at(&path);
+        assert_eq!(store.load(), None);
+
+        store.store(" key-1\n").unwrap();
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        store.forget().unwrap();
+        assert_eq!(store.load(), None);
+        store.forget().unwrap();
+    }
+
+    #[test]
+    fn file_store_ignores_blank_key() {
+        let path = temp_path("license.key");
+        let store = FileStore::at(&path);
+        store.store("  ").unwrap();
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn file_store_migrates_legacy_key() {
+        let legacy = temp_path("license.data");
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        std::fs::write(&legacy, "old-key").unwrap();
+
+        let store = FileStore::at(temp_path("license.key"));
+        store.migrate_from(&legacy).unwrap();
+        assert_eq!(store.load().as_deref(), Some("old-key"));
+        assert!(!legacy.exists());
+
+        // a key already in the store wins over a legacy one
+        std::fs::write(&legacy, "older-key").unwrap();
+        store.migrate_from(&legacy).unwrap();
+        assert_eq!(store.load().as_deref(), Some("old-key"));
+    }
+
+    #[test]
+    fn env_store_reads_only() {
+        let var = format!("LICENSE_KEY_TEST_{}", rand::random::<u64>());
+        let store = EnvStore::new(&var);
+        assert_eq!(store.load(), None);
+
+        std::env::set_var(&var, " key-1 ");
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+        assert_eq!(
+            store.store("key-2").unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert!(store.forget().is_err());
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+
+        std::env::set_var(&var, "");
+        assert_eq!(store.load(), None);
+        std::env::remove_var(&var);
+    }
+
+    #[test]
+    fn memory_store_round_trip() {
+        let store = MemoryStore::default();
+        assert_eq!(store.load(), None);
+
+        store.store("key-1 ").unwrap();
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+        assert_eq!(MemoryStore::new("key-2").load().as_deref(), Some("key-2"));
+
+        store.forget().unwrap();
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn encrypted_store_round_trip() {
+        let path = temp_path("license.enc");
+        let store = EncryptedFileStore::new(&path, "machine-a");
+        assert_eq!(store.load(), None);
+
+        store.store("key-1").unwrap();
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+        let file = std::fs::read(&path).unwrap();
+        assert!(!file.windows(5).any(|window| window == b"key-1"));
+
+        // every write derives its cipher key from a fresh salt
+        store.store("key-1").unwrap();
+        let rewritten = std::fs::read(&path).unwrap();
+        let salt = ..EncryptedFileStore::SALT_LEN;
+        assert_ne!(file[salt], rewritten[salt]);
+        assert_eq!(store.load().as_deref(), Some("key-1"));
+
+        store.forget().unwrap();
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn encrypted_store_rejects_wrong_passphrase() {
+        let path = temp_path("license.enc");
+        EncryptedFileStore::new(&path, "machine-a")
+            .store("key-1")
+            .unwrap();
+
+        assert_eq!(EncryptedFileStore::new(&path, "machine-b").load(), None);
+
+        // tampering is caught as well
+        let mut file = std::fs::read(&path).unwrap();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        std::fs::write(&path, file).unwrap();
+        assert_eq!(EncryptedFileStore::new(&path, "machine-a").load(), None);
+    }
+}
@@ -1,27 +1,37 @@
-use std::{
-    convert::Infallible,
-    marker::PhantomData,
-    net::{IpAddr, SocketAddr},
-    str::FromStr,
-    sync::Arc,
-};
+#![allow(clippy::result_large_err)] // ConnectionError wraps tonic::Status
+
+use std::{marker::PhantomData, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::Utc;
 
 use client::{
-    connection::{Connection, ConnectionError, ConnectionState},
-    ErrorDispatcher,
+    connection::{ConnectionError, ConnectionState},
+    supervisor::{self, Supervisor},
+    ErrorDispatcher, FailurePolicy,
+};
+use gui::{Dispatcher, GUIBackend};
+use keystore::{FileStore, KeyStore};
+use license::{LicenseHandle, LicenseSender, LicenseStatus};
+use proto::software::v1::{
+    authority_client::AuthorityClient, LicenseError, ReleaseMachineRequest, VerifyingKey,
 };
-use gui::GUIBackend;
-use proto::software::v1::{authority_client::AuthorityClient, ClientHearthbeat, VerifyingKey};
 use rand::{rngs::StdRng, SeedableRng};
 use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint};
 
-pub trait DataVerifier: Send + 'static {
-    fn verify(&self, data: serde_json::Value) -> bool;
+pub trait DataVerifier: Send + Sync + 'static {
+    /// What the host application gets to read from a verified license
+    type Data: Send + Sync + 'static;
+
+    /// Checks the license's `extra_data`, `None` rejects the license
+    fn verify(&self, data: serde_json::Value) -> Option<Self::Data>;
 }
 
 pub struct FuncVerifier<T, Functor> {
     functor: Functor,
-    _p: PhantomData<T>,
+    // holds no `T`, so it doesn't decide whether the verifier is `Send` or `Sync`
+    _p: PhantomData<fn(T)>,
 }
 
 impl<T, Functor> FuncVerifier<T, Functor> {
@@ -33,30 +43,87 @@ impl<T, Functor> FuncVerifier<T, Functor> {
     }
 }
 
-impl<T: DeserializeOwned + Send + 'static, Functor: Send + 'static> DataVerifier
-    for FuncVerifier<T, Functor>
+impl<T, Functor> DataVerifier for FuncVerifier<T, Functor>
 where
-    Functor: Fn(T) -> bool,
+    T: DeserializeOwned + Send + Sync + 'static,
+    Functor: Fn(&T) -> bool + Send + Sync + 'static,
 {
-    fn verify(&self, data: serde_json::Value) -> bool {
-        serde_json::from_value(data)
-            .map(|data| (&self.functor)(data))
-            .unwrap_or(false)
+    type Data = T;
+
+    fn verify(&self, data: serde_json::Value) -> Option<T> {
+        let data: T = serde_json::from_value(data).ok()?;
+        (self.functor)(&data).then_some(data)
     }
 }
 
 impl DataVerifier for () {
-    fn verify(&self, _: serde_json::Value) -> bool {
-        true
+    type Data = serde_json::Value;
+
+    fn verify(&self, data: serde_json::Value) -> Option<serde_json::Value> {
+        Some(data)
     }
 }
 
+// owned, so setters chain with the custom ones and `build` doesn't need a `Clone` verifier
 #[derive(derive_builder::Builder)]
+#[builder(pattern = "owned")]
 pub struct ClientInput<Verifier: DataVerifier = ()> {
     #[builder(setter(custom))]
     pub verifier: Verifier,
     pub addr: String,
     pub verifying_key: String,
+    /// Path of a signed license file, used instead of the server while it is valid.
+    /// Offline licenses are off unless it is set
+    #[builder(default)]
+    pub offline_license: Option<String>,
+    /// How long to keep running on the last verified response while the server is unreachable
+    #[builder(default = "DEFAULT_GRACE_PERIOD")]
+    pub grace_period: Duration,
+    /// Where the license key is kept. Defaults to a [`FileStore`] in the user's
+    /// config directory, named after the verifying key
+    #[builder(default, setter(custom))]
+    pub key_store: Option<Arc<dyn KeyStore>>,
+    /// How the user is asked for a key and told about the license. Defaults to a
+    /// [`Dispatcher`] picking a window or the terminal
+    #[builder(default, setter(custom))]
+    pub gui: Option<Arc<dyn GUIBackend>>,
+    /// What happens once the license check gives up. Defaults to [`FailurePolicy::Exit`]
+    #[builder(default)]
+    pub failure_policy: FailurePolicy,
+}
+
+impl<Verifier: DataVerifier> ClientInput<Verifier> {
+    fn parse_verifying_key(&self) -> Result<VerifyingKey, ConnectionError> {
+        VerifyingKey::from_str(self.verifying_key.trim())
+            .map_err(|_| ConnectionError::BadVerifyingKey)
+    }
+
+    fn key_store(&self, verifying_key: &VerifyingKey) -> Arc<dyn KeyStore> {
+        match &self.key_store {
+            Some(store) => store.clone(),
+            None => {
+                let store = FileStore::config_dir(&app_dir_name(verifying_key));
+                // a key that can't be moved only means asking for it again
+                let _ = store.migrate_legacy();
+                Arc::new(store)
+            }
+        }
+    }
+
+    fn gui(&self) -> Arc<dyn GUIBackend> {
+        match &self.gui {
+            Some(gui) => gui.clone(),
+            None => Arc::new(Dispatcher::new()),
+        }
+    }
+
+    /// Client for the configured server, connecting on first use
+    fn authority_client(&self) -> Result<AuthorityClient<Channel>, ConnectionError> {
+        let endpoint = Endpoint::from_shared(self.addr.clone()).map_err(|_| {
+            ConnectionError::InvalidConfig(format!("invalid server address `{}`", self.addr))
+        })?;
+        Ok(AuthorityClient::new(endpoint.connect_lazy()))
+    }
 }
 
 impl<Verifier: DataVerifier> ClientInputBuilder<Verifier> {
@@ -65,76 +132,283 @@ impl<Verifier: DataVerifier> ClientInputBuilder<Verifier> {
             verifier: Some(v),
             addr: self.addr,
             verifying_key: self.verifying_key,
+            offline_license: self.offline_license,
+            grace_period: self.grace_period,
+            key_store: self.key_store,
+            gui: self.gui,
+            failure_policy: self.failure_policy,
         }
     }
+
+    pub fn key_store(mut self, store: impl KeyStore) -> Self {
+        self.key_store = Some(Some(Arc::new(store)));
+        self
+    }
+
+    pub fn gui(mut self, gui: impl GUIBackend) -> Self {
+        self.gui = Some(Some(Arc::new(gui)));
+        self
+    }
+}
+
+/// Names the app's directory under the user's config directory after its verifying key
+fn app_dir_name(verifying_key: &VerifyingKey) -> String {
+    let app = verifying_key.to_string();
+    format!("license-{}", &app[..16])
 }
 
-const LICFILE_PATH: &str = "./license.data";
+/// Where license state other than the key is kept, next to the default [`FileStore`]
+pub(crate) fn app_config_dir(verifying_key: &VerifyingKey) -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_default()
+        .join(app_dir_name(verifying_key))
+}
+
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
 
 pub struct Connector;
 
 impl Connector {
-    fn try_load_key() -> Result<String, ()> {
-        std::fs::read_to_string(LICFILE_PATH).map_err(|_| ())
+    /// The stored key, or one the user is prompted for and that gets stored
+    pub fn load_key(store: &dyn KeyStore, gui: &dyn GUIBackend) -> String {
+        match store.load() {
+            Some(key) => key,
+            None => {
+                let key = gui.prompt_license();
+                if !key.trim().is_empty() {
+                    // an unsaved key only means asking again next time
+                    let _ = store.store(&key);
+                }
+                key
+            }
+        }
     }
 
-    fn prompt_and_save_key(gui: &impl GUIBackend) -> String {
-        let key = gui.prompt_license();
-        let _ = std::fs::write(LICFILE_PATH, key.as_str());
-        key
+    /// Replaces the stored license key, e.g. with a new one after `InvalidKey`
+    pub fn replace_key<V: DataVerifier>(
+        input: &ClientInput<V>,
+        key: &str,
+    ) -> Result<(), ConnectionError> {
+        let verifying_key = input.parse_verifying_key()?;
+        input.key_store(&verifying_key).store(key)?;
+        Ok(())
     }
 
-    pub fn load_key(gui: &impl GUIBackend) -> String {
-        match Self::try_load_key() {
-            Ok(key) => key,
-            Err(_) => Self::prompt_and_save_key(gui),
-        }
+    /// Forgets the stored license key, so the user is prompted on the next start
+    pub fn forget_key<V: DataVerifier>(input: &ClientInput<V>) -> Result<(), ConnectionError> {
+        let verifying_key = input.parse_verifying_key()?;
+        input.key_store(&verifying_key).forget()?;
+        Ok(())
     }
 
-    // this function is allowed to panic because we need to crash if something is wrong
-    async fn new<V: DataVerifier>(input: ClientInput<V>) -> client::connection::ConnectionState<V> {
-        let endpoint = tonic::transport::channel::Endpoint::from_shared(input.addr).unwrap();
-        let client = AuthorityClient::connect(endpoint).await.unwrap();
-
-        let gui = crate::gui::Dispatcher::new();
+    #[allow(clippy::new_ret_no_self)]
+    fn new<V: DataVerifier>(
+        input: ClientInput<V>,
+        key_store: &dyn KeyStore,
+    ) -> Result<client::connection::ConnectionState<V>, ConnectionError> {
+        // an unreachable server only surfaces on the first call, as a transient error
+        let client = input.authority_client()?;
+        let verifying_key = input.parse_verifying_key()?;
 
-        let verifying_key = VerifyingKey::from_str(&input.verifying_key).unwrap();
-        let license_key = Self::load_key(&gui);
+        let gui = input.gui();
+        let license_key = Self::load_key(key_store, &*gui);
 
-        let gui = Arc::new(gui);
-
-        let state = ConnectionState {
+        Ok(ConnectionState {
             client,
             license_key,
             rng: StdRng::from_os_rng(),
+            machine_id: fingerprint::machine_id(&verifying_key).unwrap_or_default(),
             verification_key: verifying_key,
-            data_verifier: input.verifier,
+            data_verifier: Arc::new(input.verifier),
+            license: Arc::new(LicenseSender::new()),
+            resumption_token: Default::default(),
             gui,
+        })
+    }
+
+    fn setup_offline<V: DataVerifier>(
+        input: &ClientInput<V>,
+        verified: offline::VerifiedFile<V::Data>,
+    ) -> LicenseHandle<V::Data> {
+        let gui = input.gui();
+        let err_dispatcher = ErrorDispatcher {
+            gui: gui.clone(),
+            policy: input.failure_policy.clone(),
         };
-        state
+
+        gui.show_license_details(verified.info);
+        let sender = LicenseSender::new();
+        sender.publish(verified.license);
+        let handle = sender.handle().expect("license was just published");
+
+        // no server will tell us, so the file's own window ends the license
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sender.until_expired(verified.not_after) => {
+                    let error = ConnectionError::LicenseError(LicenseError::Expired);
+                    sender.set_status(LicenseStatus::from_error(&error));
+                    let _ = err_dispatcher.dispatch(error);
+                }
+                _ = sender.close_requested() => {}
+            }
+        });
+        handle
     }
 
-    pub async fn setup<V: DataVerifier>(input: ClientInput<V>) -> Result<(), ConnectionError> {
-        let state = Self::new(input).await;
-        let gui = state.gui.clone();
-        let connection = client::connection::Connection::new(state).await.unwrap();
+    /// Writes an offline activation request for this machine to `path`.
+    /// The admin answers it with the `ActivateOffline` RPC.
+    pub fn request_activation<V: DataVerifier>(
+        input: &ClientInput<V>,
+        path: &str,
+    ) -> Result<(), ConnectionError> {
+        let verifying_key = input.parse_verifying_key()?;
+        let license_key = Self::load_key(&*input.key_store(&verifying_key), &*input.gui());
+        offline::write_activation_request(&license_key, &verifying_key, path)
+    }
 
-        let err_dispatcher = ErrorDispatcher { gui };
+    /// Imports the admin's answer to [`Connector::request_activation`] into
+    /// [`ClientInput::offline_license`], after which [`Connector::setup`] works without the server
+    pub fn import_activation<V: DataVerifier>(
+        input: &ClientInput<V>,
+        path: &str,
+    ) -> Result<(), ConnectionError> {
+        let verifying_key = input.parse_verifying_key()?;
+        let Some(license_path) = &input.offline_license else {
+            return Err(ConnectionError::InvalidConfig(
+                "offline_license must be set to import an activation".to_owned(),
+            ));
+        };
+        let response = std::fs::read(path)?;
+        offline::import_activation_response(&response, &verifying_key, &input.verifier)?;
+        std::fs::write(license_path, response)?;
+        Ok(())
+    }
 
-        let connection = match connection.authorize().await {
-            Ok(conn) => conn,
+    /// Frees this machine's activation so the license can be moved, e.g. before reinstalling.
+    /// Sessions still open on this machine are ended. Returns whether the machine was activated.
+    ///
+    /// The server trusts the license key and this machine's fingerprint alone, so anyone
+    /// holding both can release the machine.
+    pub async fn release_machine<V: DataVerifier>(
+        input: &ClientInput<V>,
+    ) -> Result<bool, ConnectionError> {
+        let verifying_key = input.parse_verifying_key()?;
+        let mut client = input.authority_client()?;
 
-            Err(err) => return err_dispatcher.dispatch(err),
+        let Some(license_key) = input.key_store(&verifying_key).load() else {
+            return Ok(false);
+        };
+        let Some(machine_id) = fingerprint::machine_id(&verifying_key) else {
+            return Ok(false);
         };
 
-        tokio::task::spawn(async move {
-            let Err(e) = connection.work().await;
-            err_dispatcher.dispatch(e).unwrap();
-        });
+        let response = client
+            .release_machine(ReleaseMachineRequest {
+                key_id: license_key.trim().to_owned(),
+                machine_id,
+            })
+            .await
+            .map_err(ConnectionError::from_call)?;
+        Ok(response.into_inner().released)
+    }
 
-        Ok(())
+    /// Checks the license and keeps it checked in the background. The returned handle
+    /// follows license changes pushed by the server.
+    ///
+    /// Configuration errors, and a server that can't be reached without a cached response
+    /// to fall back on, are returned. License failures go through the [`FailurePolicy`] and
+    /// are returned as well if it lets the app run, as there is no license to hand out.
+    /// [`FailurePolicy::Retry`] returns only once the server accepted the license instead.
+    pub async fn setup<V: DataVerifier>(
+        input: ClientInput<V>,
+    ) -> Result<LicenseHandle<V::Data>, ConnectionError> {
+        if let Some(Ok(file)) = input.offline_license.as_ref().map(std::fs::read) {
+            let verifying_key = input.parse_verifying_key()?;
+            match offline::verify(&file, &verifying_key, &input.verifier) {
+                Ok(verified) => return Ok(Self::setup_offline(&input, verified)),
+                // the server may still vouch for the license, e.g. after it was extended
+                Err(err) => tracing::warn!("offline.license_rejected: {err}"),
+            }
+        }
+
+        let grace_period = input.grace_period;
+        let policy = input.failure_policy.clone();
+        let key_store = input.key_store(&input.parse_verifying_key()?);
+        let state = Self::new(input, &*key_store)?;
+        let supervisor = Supervisor {
+            err_dispatcher: ErrorDispatcher {
+                gui: state.gui.clone(),
+                policy,
+            },
+            state,
+            grace_period,
+        };
+
+        // a policy that keeps retrying also waits out the seat of a session that just ended
+        let retrying = supervisor.err_dispatcher.keeps_retrying();
+        let retry = if retrying {
+            Supervisor::<V>::is_retriable
+        } else {
+            ConnectionError::is_transient
+        };
+        let startup_deadline = Instant::now() + supervisor::STARTUP_RETRY_WINDOW;
+        let mut connection = None;
+        let mut grace_end = Instant::now();
+        match supervisor.connect_until(startup_deadline, retry).await {
+            Ok(connected) => connection = Some(connected),
+            Err(err) => {
+                let state = &supervisor.state;
+                let cached = err
+                    .is_transient()
+                    .then(|| {
+                        cache::load(&state.verification_key, &*state.data_verifier, grace_period)
+                    })
+                    .flatten();
+                if let Some((info, license, until)) = cached {
+                    state.license.publish(license);
+                    state
+                        .license
+                        .set_status(LicenseStatus::OfflineGrace { until });
+                    state.gui.show_license_details(info);
+                    grace_end += (until - Utc::now()).to_std().unwrap_or_default();
+                } else if retrying {
+                    // hands the app a license only once the server accepts it, however long
+                    connection = supervisor.recover(err).await;
+                } else if err.is_transient() {
+                    // nothing was decided about the license, leave it to the caller
+                    return Err(err);
+                } else {
+                    if matches!(err, ConnectionError::LicenseError(LicenseError::InvalidKey)) {
+                        // signed by the server, the key would be rejected again: ask for a new one
+                        let _ = key_store.forget();
+                    }
+                    return Err(supervisor.err_dispatcher.dispatch(err));
+                }
+            }
+        }
+        if let Some(connection) = &connection {
+            // only the first check is shown, reconnects happen behind the app's back
+            supervisor
+                .state
+                .gui
+                .show_license_details(connection.details().clone());
+        }
+
+        let handle = supervisor
+            .state
+            .license
+            .handle()
+            .expect("a license is published before the check succeeds");
+        tokio::task::spawn(supervisor.run(connection, grace_end));
+
+        Ok(handle)
     }
 }
 
+pub mod cache;
 pub mod client;
+pub mod fingerprint;
 pub mod gui;
+pub mod keystore;
+pub mod license;
+pub mod offline;
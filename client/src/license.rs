@@ -0,0 +1,350 @@
+//! The verified license as seen by the host application
+
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use proto::{
+    software::v1::{info_response, LicenseError},
+    ChronoExt,
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{client::connection::ConnectionError, DataVerifier};
+
+/// License details that passed signature and [`DataVerifier`] checks
+#[derive(Debug)]
+pub struct License<T> {
+    /// what the verifier made of the license's `extra_data`
+    pub data: T,
+    pub expiry: DateTime<Utc>,
+    pub holder: String,
+    extra_data: String,
+}
+
+impl<T> License<T> {
+    /// Runs the verifier over a response whose signature was already checked
+    pub fn verify<V: DataVerifier<Data = T>>(
+        info: &info_response::Response,
+        verifier: &V,
+    ) -> Result<Self, ConnectionError> {
+        let extra_data = serde_json::from_str(&info.extra_data)
+            .map_err(|_| ConnectionError::DataVerificationError)?;
+        let data = verifier
+            .verify(extra_data)
+            .ok_or(ConnectionError::DataVerificationError)?;
+
+        Ok(Self {
+            data,
+            expiry: info
+                .expiry
+                .as_ref()
+                .and_then(DateTime::try_from_protobuf)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            holder: info.holder.clone(),
+            extra_data: info.extra_data.clone(),
+        })
+    }
+
+    fn same_as(&self, other: &Self) -> bool {
+        self.expiry == other.expiry
+            && self.holder == other.holder
+            && self.extra_data == other.extra_data
+    }
+}
+
+/// How close to its expiry a license is reported as [`LicenseStatus::Expiring`]
+pub const EXPIRING_SOON: TimeDelta = TimeDelta::days(7);
+
+/// Where the license stands, for apps that want to react instead of being stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LicenseStatus {
+    Valid,
+    /// valid, but expires within [`EXPIRING_SOON`]
+    Expiring,
+    /// the server can't be reached, the last verified license is trusted until `until`
+    OfflineGrace {
+        until: DateTime<Utc>,
+    },
+    Revoked,
+    Expired,
+    /// rejected for any other reason, or no longer verifiable
+    Invalid,
+}
+
+impl LicenseStatus {
+    fn of<T>(license: &License<T>) -> Self {
+        let now = Utc::now();
+        if now > license.expiry {
+            LicenseStatus::Expired
+        } else if license.expiry - now < EXPIRING_SOON {
+            LicenseStatus::Expiring
+        } else {
+            LicenseStatus::Valid
+        }
+    }
+
+    /// Status after the license check gave up with `error`
+    pub fn from_error(error: &ConnectionError) -> Self {
+        match error {
+            ConnectionError::LicenseError(LicenseError::Revoked) => LicenseStatus::Revoked,
+            ConnectionError::LicenseError(LicenseError::Expired) => LicenseStatus::Expired,
+            _ => LicenseStatus::Invalid,
+        }
+    }
+}
+
+/// Publishing side of [`LicenseHandle`], shared by every connection attempt
+pub struct LicenseSender<T> {
+    license: watch::Sender<Option<Arc<License<T>>>>,
+    status: watch::Sender<LicenseStatus>,
+    close: Arc<Notify>,
+}
+
+impl<T> LicenseSender<T> {
+    pub fn new() -> Self {
+        Self {
+            license: watch::Sender::new(None),
+            status: watch::Sender::new(LicenseStatus::Invalid),
+            close: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Replaces the license, waking up handles only if something actually changed
+    pub fn publish(&self, license: License<T>) {
+        self.set_status(LicenseStatus::of(&license));
+        self.license.send_if_modified(|current| {
+            if current
+                .as_ref()
+                .is_some_and(|current| current.same_as(&license))
+            {
+                return false;
+            }
+            *current = Some(Arc::new(license));
+            true
+        });
+    }
+
+    pub fn set_status(&self, status: LicenseStatus) {
+        self.status.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+
+    /// Resolves at `not_after`, moving the status to [`LicenseStatus::Expiring`] on the
+    /// way. Keeps the status of licenses no server reports on, such as offline ones, current.
+    pub async fn until_expired(&self, not_after: DateTime<Utc>) {
+        let sleep_until =
+            |at: DateTime<Utc>| tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default());
+        if let Some(license) = self.current() {
+            let expiring_at = license.expiry - EXPIRING_SOON;
+            if expiring_at < not_after {
+                sleep_until(expiring_at).await;
+                self.set_status(LicenseStatus::Expiring);
+            }
+        }
+        sleep_until(not_after).await;
+    }
+
+    /// The last published license
+    pub fn current(&self) -> Option<Arc<License<T>>> {
+        self.license.borrow().clone()
+    }
+
+    /// Handle for the host application, once a license was published
+    pub fn handle(&self) -> Option<LicenseHandle<T>> {
+        self.license.borrow().is_some().then(|| LicenseHandle {
+            rx: self.license.subscribe(),
+            status: self.status.subscribe(),
+            close: self.close.clone(),
+        })
+    }
+
+    /// Resolves once a handle asked for the check to stop
+    pub async fn close_requested(&self) {
+        self.close.notified().await
+    }
+}
+
+impl<T> Default for LicenseSender<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Live view of the license, updated when the server pushes changes
+pub struct LicenseHandle<T> {
+    rx: watch::Receiver<Option<Arc<License<T>>>>,
+    status: watch::Receiver<LicenseStatus>,
+    close: Arc<Notify>,
+}
+
+impl<T> Clone for LicenseHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            rx: self.rx.clone(),
+            status: self.status.clone(),
+            close: self.close.clone(),
+        }
+    }
+}
+
+impl<T> LicenseHandle<T> {
+    /// The latest verified license
+    pub fn current(&self) -> Arc<License<T>> {
+        self.rx
+            .borrow()
+            .clone()
+            .expect("handles are only created once a license is known")
+    }
+
+    /// Waits for the next change, fails once the license is no longer checked
+    pub async fn changed(&mut self) -> Result<Arc<License<T>>, watch::error::RecvError> {
+        self.rx.changed().await?;
+        Ok(self.current())
+    }
+
+    pub fn status(&self) -> LicenseStatus {
+        *self.status.borrow()
+    }
+
+    /// Stream of status changes. The last status stays readable after the check stops.
+    pub fn status_changes(&self) -> watch::Receiver<LicenseStatus> {
+        self.status.clone()
+    }
+
+    /// Stops checking the license and gives the session's seat back to the server right
+    /// away, so a restarted app isn't refused with `TooManySessions`. Call it on shutdown.
+    pub async fn close(&self) {
+        self.close.notify_one();
+        // the sender goes away with the check
+        let mut rx = self.rx.clone();
+        while rx.changed().await.is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn license(expires_in: TimeDelta) -> License<()> {
+        License {
+            data: (),
+            expiry: Utc::now() + expires_in,
+            holder: "holder".to_owned(),
+            extra_data: "{}".to_owned(),
+        }
+    }
+
+    #[test]
+    fn status_follows_expiry() {
+        assert_eq!(
+            LicenseStatus::of(&license(TimeDelta::days(30))),
+            LicenseStatus::Valid
+        );
+        assert_eq!(
+            LicenseStatus::of(&license(TimeDelta::days(1))),
+            LicenseStatus::Expiring
+        );
+        assert_eq!(
+            LicenseStatus::of(&license(-TimeDelta::seconds(1))),
+            LicenseStatus::Expired
+        );
+    }
+
+    #[test]
+    fn status_from_error() {
+        let license_error = |error| ConnectionError::LicenseError(error);
+        assert_eq!(
+            LicenseStatus::from_error(&license_error(LicenseError::Revoked)),
+            LicenseStatus::Revoked
+        );
+        assert_eq!(
+            LicenseStatus::from_error(&license_error(LicenseError::Expired)),
+            LicenseStatus::Expired
+        );
+        assert_eq!(
+            LicenseStatus::from_error(&license_error(LicenseError::TooManySessions)),
+            LicenseStatus::Invalid
+        );
+        assert_eq!(
+            LicenseStatus::from_error(&ConnectionError::InvalidSignature),
+            LicenseStatus::Invalid
+        );
+    }
+
+    #[test]
+    fn sender_publishes_transitions() {
+        let sender = LicenseSender::new();
+        assert!(sender.handle().is_none());
+
+        sender.publish(license(TimeDelta::days(30)));
+        let handle = sender.handle().unwrap();
+        let mut changes = handle.status_changes();
+        assert_eq!(handle.status(), LicenseStatus::Valid);
+
+        let until = Utc::now() + TimeDelta::hours(1);
+        sender.set_status(LicenseStatus::OfflineGrace { until });
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(
+            *changes.borrow_and_update(),
+            LicenseStatus::OfflineGrace { until }
+        );
+
+        // the same status again wakes nobody up
+        sender.set_status(LicenseStatus::OfflineGrace { until });
+        assert!(!changes.has_changed().unwrap());
+
+        sender.publish(license(TimeDelta::days(1)));
+        assert_eq!(*changes.borrow_and_update(), LicenseStatus::Expiring);
+        assert_eq!(handle.current().expiry, sender.current().unwrap().expiry);
+
+        sender.set_status(LicenseStatus::Revoked);
+        assert_eq!(handle.status(), LicenseStatus::Revoked);
+
+        // the last status stays readable once the check stops
+        drop(sender);
+        assert!(changes.has_changed().is_err());
+        assert_eq!(handle.status(), LicenseStatus::Revoked);
+    }
+
+    #[tokio::test]
+    async fn unreported_license_becomes_expiring() {
+        let sender = LicenseSender::new();
+        sender.publish(license(EXPIRING_SOON + TimeDelta::milliseconds(50)));
+        let handle = sender.handle().unwrap();
+        assert_eq!(handle.status(), LicenseStatus::Valid);
+
+        let not_after = Utc::now() + TimeDelta::milliseconds(100);
+        sender.until_expired(not_after).await;
+        assert!(Utc::now() >= not_after);
+        assert_eq!(handle.status(), LicenseStatus::Expiring);
+
+        // a window ending before the license expires soon never reports it as expiring
+        sender.publish(license(TimeDelta::days(30)));
+        sender
+            .until_expired(Utc::now() + TimeDelta::milliseconds(10))
+            .await;
+        assert_eq!(handle.status(), LicenseStatus::Valid);
+    }
+
+    #[test]
+    fn republishing_same_license_is_quiet() {
+        let sender = LicenseSender::new();
+        let first = license(TimeDelta::days(30));
+        let expiry = first.expiry;
+        sender.publish(first);
+        let mut handle = sender.handle().unwrap();
+        handle.rx.mark_unchanged();
+
+        sender.publish(License {
+            expiry,
+            ..license(TimeDelta::days(30))
+        });
+        assert!(!handle.rx.has_changed().unwrap());
+
+        sender.publish(license(TimeDelta::days(60)));
+        assert!(handle.rx.has_changed().unwrap());
+    }
+}
@@ -0,0 +1,265 @@
+//! Signed license files for machines that can't reach the license server
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use prost::Message;
+use proto::{
+    software::v1::{
+        info_response, ActivationRequest, OfflineLicense, OfflineLicenseError, VerifyingKey,
+    },
+    ChronoExt,
+};
+
+use crate::{client::connection::ConnectionError, fingerprint, license::License, DataVerifier};
+
+/// Copy of the last activation request, kept to match the response against
+fn pending_activation_path(verifying_key: &VerifyingKey) -> PathBuf {
+    crate::app_config_dir(verifying_key).join("activation.pending")
+}
+
+impl From<OfflineLicenseError> for ConnectionError {
+    fn from(err: OfflineLicenseError) -> Self {
+        match err {
+            OfflineLicenseError::InvalidSignature => ConnectionError::InvalidSignature,
+            OfflineLicenseError::Expired => {
+                ConnectionError::LicenseError(proto::software::v1::LicenseError::Expired)
+            }
+            OfflineLicenseError::Malformed
+            | OfflineLicenseError::NotYetValid
+            | OfflineLicenseError::WrongMachine => ConnectionError::InvalidOfflineLicense,
+        }
+    }
+}
+
+/// A license file that passed [`verify`]
+pub struct VerifiedFile<T> {
+    pub info: info_response::Response,
+    pub license: License<T>,
+    /// end of the file's validity window, never later than the license's expiry
+    pub not_after: DateTime<Utc>,
+}
+
+/// Checks a license file issued by the server the same way an online check would be
+pub fn verify<V: DataVerifier>(
+    file: &[u8],
+    verifying_key: &VerifyingKey,
+    verifier: &V,
+) -> Result<VerifiedFile<V::Data>, ConnectionError> {
+    let file = OfflineLicense::decode(file).map_err(|_| ConnectionError::InvalidOfflineLicense)?;
+    let machine_id = fingerprint::machine_id(verifying_key).unwrap_or_default();
+    let payload = file.verify(verifying_key, &machine_id, Utc::now())?;
+
+    let Some(info) = payload.license.clone() else {
+        return Err(ConnectionError::InvalidOfflineLicense);
+    };
+    let not_after = payload
+        .not_after
+        .as_ref()
+        .and_then(DateTime::try_from_protobuf)
+        .ok_or(ConnectionError::InvalidOfflineLicense)?;
+    let license = License::verify(&info, verifier)?;
+    Ok(VerifiedFile {
+        info,
+        license,
+        not_after,
+    })
+}
+
+/// Writes an activation request for this machine to `path`, to be answered by an admin
+pub fn write_activation_request(
+    key_id: &str,
+    verifying_key: &VerifyingKey,
+    path: &str,
+) -> Result<(), ConnectionError> {
+    let machine_id =
+        fingerprint::machine_id(verifying_key).ok_or(ConnectionError::UnknownMachine)?;
+    let request = ActivationRequest {
+        key_id: key_id.trim().to_owned(),
+        machine_id,
+        nonce: rand::random(),
+    }
+    .encode_to_vec();
+
+    let pending = pending_activation_path(verifying_key);
+    if let Some(dir) = pending.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(pending, &request)?;
+    std::fs::write(path, &request)?;
+    Ok(())
+}
+
+/// Checks an activation response against the pending request, like [`verify`] does for files
+pub fn import_activation_response(
+    response: &[u8],
+    verifying_key: &VerifyingKey,
+    verifier: &impl DataVerifier,
+) -> Result<(), ConnectionError> {
+    let pending_path = pending_activation_path(verifying_key);
+    let pending = std::fs::read(&pending_path)?;
+    let pending = ActivationRequest::decode(pending.as_slice())
+        .map_err(|_| ConnectionError::InvalidOfflineLicense)?;
+
+    let file =
+        OfflineLicense::decode(response).map_err(|_| ConnectionError::InvalidOfflineLicense)?;
+    // the signature covers the nonce, so a matching one proves the response answers our request
+    let answers_request = file.nonce == pending.nonce
+        && file
+            .payload
+            .as_ref()
+            .is_some_and(|payload| payload.key_id == pending.key_id);
+    if !answers_request {
+        return Err(ConnectionError::InvalidOfflineLicense);
+    }
+
+    verify(response, verifying_key, verifier)?;
+    let _ = std::fs::remove_file(pending_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use proto::software::v1::{offline_license, LicenseError, SigningKey};
+
+    use super::*;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::try_from(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    fn verifying_key(signer: &SigningKey) -> VerifyingKey {
+        VerifyingKey(signer.verifying_key())
+    }
+
+    fn this_machine(verifying_key: &VerifyingKey) -> String {
+        fingerprint::machine_id(verifying_key).unwrap_or_default()
+    }
+
+    fn payload(machine_id: String, not_after: DateTime<Utc>) -> offline_license::Payload {
+        offline_license::Payload {
+            key_id: "key".to_owned(),
+            license: Some(info_response::Response {
+                expiry: Some((Utc::now() + TimeDelta::days(30)).to_protobuf()),
+                extra_data: "{}".to_owned(),
+                issued_at: Some(Utc::now().to_protobuf()),
+                holder: "holder".to_owned(),
+                resumption_token: Vec::new(),
+            }),
+            not_before: Some((Utc::now() - TimeDelta::hours(1)).to_protobuf()),
+            not_after: Some(not_after.to_protobuf()),
+            machine_id,
+        }
+    }
+
+    fn file(payload: offline_license::Payload, nonce: u64, signer: &mut SigningKey) -> Vec<u8> {
+        OfflineLicense::sign(payload, nonce, signer).encode_to_vec()
+    }
+
+    #[test]
+    fn verify_accepts_file_for_this_machine() {
+        let mut signer = signing_key();
+        let key = verifying_key(&signer);
+        let not_after = Utc::now() + TimeDelta::days(1);
+        let file = file(payload(this_machine(&key), not_after), 1, &mut signer);
+
+        let verified = verify(&file, &key, &()).unwrap();
+        assert_eq!(verified.license.holder, "holder");
+        assert_eq!(verified.not_after.timestamp(), not_after.timestamp());
+    }
+
+    #[test]
+    fn verify_rejects_bad_signature() {
+        let key = verifying_key(&signing_key());
+        let payload = payload(this_machine(&key), Utc::now() + TimeDelta::days(1));
+        let file = file(payload, 1, &mut signing_key());
+
+        assert!(matches!(
+            verify(&file, &key, &()),
+            Err(ConnectionError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_other_machine() {
+        let mut signer = signing_key();
+        let key = verifying_key(&signer);
+        let payload = payload("other-machine".to_owned(), Utc::now() + TimeDelta::days(1));
+        let file = file(payload, 1, &mut signer);
+
+        assert!(matches!(
+            verify(&file, &key, &()),
+            Err(ConnectionError::InvalidOfflineLicense)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_expired_file() {
+        let mut signer = signing_key();
+        let key = verifying_key(&signer);
+        let payload = payload(this_machine(&key), Utc::now() - TimeDelta::minutes(1));
+        let file = file(payload, 1, &mut signer);
+
+        assert!(matches!(
+            verify(&file, &key, &()),
+            Err(ConnectionError::LicenseError(LicenseError::Expired))
+        ));
+    }
+
+    #[test]
+    fn import_checks_response_against_pending_request() {
+        let mut signer = signing_key();
+        let key = verifying_key(&signer);
+        let request_path =
+            std::env::temp_dir().join(format!("activation-{}", rand::random::<u64>()));
+        let request_path = request_path.to_str().unwrap();
+
+        write_activation_request("key", &key, request_path).unwrap();
+        let request =
+            ActivationRequest::decode(std::fs::read(request_path).unwrap().as_slice()).unwrap();
+        assert_eq!(request.machine_id, this_machine(&key));
+
+        let valid_until = Utc::now() + TimeDelta::days(1);
+        let rejected = [
+            file(
+                payload(request.machine_id.clone(), valid_until),
+                request.nonce + 1,
+                &mut signer,
+            ),
+            file(
+                payload(request.machine_id.clone(), valid_until),
+                request.nonce,
+                &mut signing_key(),
+            ),
+            file(
+                payload("other-machine".to_owned(), valid_until),
+                request.nonce,
+                &mut signer,
+            ),
+            file(
+                payload(
+                    request.machine_id.clone(),
+                    Utc::now() - TimeDelta::minutes(1),
+                ),
+                request.nonce,
+                &mut signer,
+            ),
+        ];
+        for response in rejected {
+            assert!(import_activation_response(&response, &key, &()).is_err());
+        }
+
+        let response = file(
+            payload(request.machine_id, valid_until),
+            request.nonce,
+            &mut signer,
+        );
+        import_activation_response(&response, &key, &()).unwrap();
+        // answered, so a second import has nothing to match against
+        assert!(import_activation_response(&response, &key, &()).is_err());
+
+        let _ = std::fs::remove_file(request_path);
+        let _ = std::fs::remove_dir_all(crate::app_config_dir(&key));
+    }
+}
@@ -1,9 +1,12 @@
 use chrono::{DateTime, TimeZone, Utc};
-use prost_types::Timestamp;
+pub use prost_types::Timestamp;
 
 pub trait ChronoExt {
     fn to_protobuf(&self) -> Timestamp;
     fn from_protobuf(ts: &Timestamp) -> Self;
+    fn try_from_protobuf(ts: &Timestamp) -> Option<Self>
+    where
+        Self: Sized;
 }
 
 impl ChronoExt for DateTime<Utc> {
@@ -15,9 +18,13 @@ impl ChronoExt for DateTime<Utc> {
     }
 
     fn from_protobuf(ts: &Timestamp) -> Self {
-        Utc.timestamp_opt(ts.seconds, ts.nanos as u32)
-            .single()
-            .expect("Invalid timestamp")
+        Self::try_from_protobuf(ts).expect("Invalid timestamp")
+    }
+
+    fn try_from_protobuf(ts: &Timestamp) -> Option<Self> {
+        u32::try_from(ts.nanos)
+            .ok()
+            .and_then(|nanos| Utc.timestamp_opt(ts.seconds, nanos).single())
     }
 }
 
@@ -26,5 +33,8 @@ pub mod software;
 pub mod admin_client {
     pub mod v1 {
         tonic::include_proto!("admin_client.v1");
+
+        /// Request metadata entry carrying the admin key
+        pub const ADMIN_KEY_METADATA: &str = "x-admin-key";
     }
 }
@@ -1,6 +1,9 @@
 use std::{fmt::Display, str::FromStr, time::Duration};
 
-use ed25519_dalek::{ed25519::signature::SignerMut, Signature, SignatureError};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{ed25519::signature::SignerMut, Signature};
+
+use crate::ChronoExt;
 
 tonic::include_proto!("software.v1");
 
@@ -14,7 +17,7 @@ pub const PORT: u16 = 5050;
 #[derive(Debug)]
 pub struct KeyError;
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 #[serde(transparent)]
 pub struct VerifyingKey(pub ed25519_dalek::VerifyingKey);
 
@@ -70,27 +73,50 @@ pub type SignatureKeypair = SigningKey;
 pub struct SignatureSchema;
 
 impl SignatureSchema {
-    fn encode<T: prost::Message>(data: &T, nonce: u64) -> Vec<u8> {
-        let mut data = data.encode_to_vec();
-        data.extend_from_slice(nonce.to_le_bytes().as_slice());
-        data
+    fn encode<T: prost::Message>(context: &[u8], data: &T, nonce: u64) -> Vec<u8> {
+        let mut encoded = context.to_vec();
+        encoded.extend_from_slice(&data.encode_to_vec());
+        encoded.extend_from_slice(nonce.to_le_bytes().as_slice());
+        encoded
     }
 
     pub fn sign<T: prost::Message>(data: &T, nonce: u64, signer: &mut SigningKey) -> Vec<u8> {
-        let data = Self::encode(data, nonce);
+        Self::sign_in(&[], data, nonce, signer)
+    }
+
+    pub fn verify<T: prost::Message>(
+        data: &T,
+        nonce: u64,
+        key: &VerifyingKey,
+        signature: &[u8],
+    ) -> bool {
+        Self::verify_in(&[], data, nonce, key, signature)
+    }
+
+    /// Signs under a `context` that must be given again to verify. Starting it with a 0 byte,
+    /// which no encoded message starts with, keeps the signature from passing for one made
+    /// by [`SignatureSchema::sign`].
+    pub fn sign_in<T: prost::Message>(
+        context: &[u8],
+        data: &T,
+        nonce: u64,
+        signer: &mut SigningKey,
+    ) -> Vec<u8> {
+        let data = Self::encode(context, data, nonce);
 
         let signature = (signer.0).sign(&data);
 
         signature.to_vec()
     }
 
-    pub fn verify<T: prost::Message>(
+    pub fn verify_in<T: prost::Message>(
+        context: &[u8],
         data: &T,
         nonce: u64,
         key: &VerifyingKey,
         signature: &[u8],
     ) -> bool {
-        let data = Self::encode(data, nonce);
+        let data = Self::encode(context, data, nonce);
 
         let Ok(signature) = Signature::from_slice(signature) else {
             return false;
@@ -99,3 +125,113 @@ impl SignatureSchema {
         (key.0).verify_strict(&data, &signature).is_ok()
     }
 }
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OfflineLicenseError {
+    Malformed,
+    InvalidSignature,
+    NotYetValid,
+    Expired,
+    WrongMachine,
+}
+
+impl OfflineLicense {
+    pub fn sign(payload: offline_license::Payload, nonce: u64, signer: &mut SigningKey) -> Self {
+        let signature = SignatureSchema::sign(&payload, nonce, signer);
+        Self {
+            payload: Some(payload),
+            nonce,
+            signature,
+        }
+    }
+
+    /// Checks the signature, the validity window at `now` and, if the file is locked
+    /// to a machine, that it is `machine_id`
+    pub fn verify(
+        &self,
+        key: &VerifyingKey,
+        machine_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<&offline_license::Payload, OfflineLicenseError> {
+        let Some(payload) = &self.payload else {
+            return Err(OfflineLicenseError::Malformed);
+        };
+        if !SignatureSchema::verify(payload, self.nonce, key, &self.signature) {
+            return Err(OfflineLicenseError::InvalidSignature);
+        }
+
+        let window = |ts: &Option<prost_types::Timestamp>| {
+            ts.as_ref()
+                .and_then(DateTime::try_from_protobuf)
+                .ok_or(OfflineLicenseError::Malformed)
+        };
+        if now < window(&payload.not_before)? {
+            return Err(OfflineLicenseError::NotYetValid);
+        }
+        if now > window(&payload.not_after)? {
+            return Err(OfflineLicenseError::Expired);
+        }
+
+        if !payload.machine_id.is_empty() && payload.machine_id != machine_id {
+            return Err(OfflineLicenseError::WrongMachine);
+        }
+
+        Ok(payload)
+    }
+}
+
+impl InfoResponse {
+    /// Signing context of rejections, which are signed over the whole response
+    const ERROR_CONTEXT: &'static [u8] = b"\0license-error";
+
+    /// Rejects the request with `nonce`, signed if the app it was made for is known
+    pub fn error(error: LicenseError, nonce: u64, signer: Option<&mut SigningKey>) -> Self {
+        let mut response = Self {
+            nonce,
+            signature: Vec::new(),
+            result: Some(info_response::Result::Error(error.into())),
+        };
+        if let Some(signer) = signer {
+            response.signature =
+                SignatureSchema::sign_in(Self::ERROR_CONTEXT, &response, nonce, signer);
+        }
+        response
+    }
+
+    /// Whether the response is a rejection signed with `key`
+    pub fn is_signed_error(&self, key: &VerifyingKey) -> bool {
+        let unsigned = Self {
+            signature: Vec::new(),
+            ..self.clone()
+        };
+        matches!(self.result, Some(info_response::Result::Error(_)))
+            && SignatureSchema::verify_in(
+                Self::ERROR_CONTEXT,
+                &unsigned,
+                self.nonce,
+                key,
+                &self.signature,
+            )
+    }
+}
+
+impl ResumptionToken {
+    /// Signing context of tokens, the 0 byte is an invalid protobuf tag
+    const CONTEXT: &'static [u8] = b"\0resumption-token";
+
+    pub fn sign(payload: resumption_token::Payload, nonce: u64, signer: &mut SigningKey) -> Self {
+        let signature = SignatureSchema::sign_in(Self::CONTEXT, &payload, nonce, signer);
+        Self {
+            payload: Some(payload),
+            nonce,
+            signature,
+        }
+    }
+
+    /// The payload, if the token was signed with `key`
+    pub fn verify(&self, key: &VerifyingKey) -> Option<&resumption_token::Payload> {
+        let payload = self.payload.as_ref()?;
+        SignatureSchema::verify_in(Self::CONTEXT, payload, self.nonce, key, &self.signature)
+            .then_some(payload)
+    }
+}
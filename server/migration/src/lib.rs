@@ -1,12 +1,22 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20250301_000002_license_revocation;
+mod m20250315_000003_create_session;
+mod m20250401_000004_create_machine;
+mod m20250410_000005_session_machine;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20250301_000002_license_revocation::Migration),
+            Box::new(m20250315_000003_create_session::Migration),
+            Box::new(m20250401_000004_create_machine::Migration),
+            Box::new(m20250410_000005_session_machine::Migration),
+        ]
     }
 }
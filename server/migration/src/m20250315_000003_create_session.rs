@@ -0,0 +1,60 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Session::Table)
+                    .if_not_exists()
+                    .col(pk_uuid(Session::Id))
+                    .col(uuid(Session::License))
+                    .col(string_null(Session::Peer))
+                    .col(timestamp(Session::StartedAt).default(Expr::current_timestamp()))
+                    .col(timestamp(Session::LastSeen).default(Expr::current_timestamp()))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_session_license")
+                            .from(Session::Table, Session::License)
+                            .to(License::Table, License::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_session_license")
+                    .table(Session::Table)
+                    .col(Session::License)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Session::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Session {
+    Table,
+    Id,
+    License,
+    Peer,
+    StartedAt,
+    LastSeen,
+}
+
+#[derive(DeriveIden)]
+enum License {
+    Table,
+    Id,
+}
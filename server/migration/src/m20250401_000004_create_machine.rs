@@ -0,0 +1,81 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(License::Table)
+                    .add_column(integer_null(License::PolicyLimitMachines))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(Machine::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Machine::Id))
+                    .col(uuid(Machine::License))
+                    .col(string(Machine::Fingerprint))
+                    .col(string_null(Machine::Label))
+                    .col(timestamp(Machine::ActivatedAt).default(Expr::current_timestamp()))
+                    .col(timestamp(Machine::LastSeen).default(Expr::current_timestamp()))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_machine_license")
+                            .from(Machine::Table, Machine::License)
+                            .to(License::Table, License::Id),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_machine_license_fingerprint")
+                    .table(Machine::Table)
+                    .col(Machine::License)
+                    .col(Machine::Fingerprint)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Machine::Table).to_owned())
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(License::Table)
+                    .drop_column(License::PolicyLimitMachines)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Machine {
+    Table,
+    Id,
+    License,
+    Fingerprint,
+    Label,
+    ActivatedAt,
+    LastSeen,
+}
+
+#[derive(DeriveIden)]
+enum License {
+    Table,
+    Id,
+    PolicyLimitMachines,
+}
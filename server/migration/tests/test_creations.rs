@@ -2,7 +2,7 @@ use migration::MigratorTrait;
 
 #[tokio::test]
 async fn test_creation() {
-    let config = "sqlite://db.data?mode=rwc".to_owned();
+    let config = "sqlite::memory:".to_owned();
 
     let conn = sea_orm::Database::connect(config).await.unwrap();
 
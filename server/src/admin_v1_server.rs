@@ -0,0 +1,761 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use prost::Message;
+use proto::{
+    admin_client::v1::{
+        license_server_server::{LicenseServer, LicenseServerServer},
+        terminate_sessions_req::Target,
+        ActivateOfflineReq, ActivateOfflineResponse, CreateAppReq, CreateAppResponse,
+        CreateLicenseReq, CreateLicenseResponse, DeactivateMachineReq, DeactivateMachineResponse,
+        ExtendLicense, ExtendLicenseResponse, IssueOfflineLicenseReq, IssueOfflineLicenseResponse,
+        LabelMachineReq, LabelMachineResponse, LicenseEvent, ListLicenseEventsReq,
+        ListLicenseEventsResponse, ListMachinesReq, ListMachinesResponse, ListSessionsReq,
+        ListSessionsResponse, MachineInfo, RevokeLicenseReq, RevokeLicenseResponse, SessionInfo,
+        TerminateSessionsReq, TerminateSessionsResponse, ADMIN_KEY_METADATA,
+    },
+    software::v1::{
+        offline_license, ActivationRequest, LicenseError, OfflineLicense, SigningKey, VerifyingKey,
+    },
+    ChronoExt, Timestamp,
+};
+use sea_orm::{
+    prelude::Uuid, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+    QueryOrder, QuerySelect, Set, TransactionTrait,
+};
+use serde_json::json;
+
+use crate::{
+    audit::{self, EventKind},
+    entities::{admin_key, app, license, license_log, machine, session},
+    sessions,
+    v1_server::license_response,
+    ServerState,
+};
+
+const DEFAULT_PAGE_SIZE: u64 = 100;
+const MAX_PAGE_SIZE: u64 = 1000;
+
+pub struct AdminV1 {
+    state: Arc<ServerState>,
+}
+
+/// Identity behind an authenticated admin call
+enum Admin {
+    Root,
+    App(admin_key::Model),
+}
+
+impl Admin {
+    fn name(&self) -> &str {
+        match self {
+            Admin::Root => "root",
+            Admin::App(key) => &key.owner,
+        }
+    }
+
+    fn check_root(&self) -> Result<(), tonic::Status> {
+        match self {
+            Admin::Root => Ok(()),
+            Admin::App(_) => Err(tonic::Status::permission_denied(
+                "operation requires the root admin key",
+            )),
+        }
+    }
+
+    /// Narrows an optional app filter down to what this admin may see
+    fn scope_app(&self, app: Option<String>) -> Result<Option<String>, tonic::Status> {
+        match self {
+            Admin::Root => Ok(app),
+            Admin::App(key) => {
+                if let Some(app) = &app {
+                    self.check_app(app)?;
+                }
+                Ok(Some(key.app.clone()))
+            }
+        }
+    }
+
+    fn check_app(&self, app: &str) -> Result<(), tonic::Status> {
+        match self {
+            Admin::Root => Ok(()),
+            Admin::App(key) if key.app == app => Ok(()),
+            Admin::App(_) => Err(tonic::Status::permission_denied(
+                "admin key is not allowed to manage this app",
+            )),
+        }
+    }
+}
+
+impl AdminV1 {
+    pub fn new(state: Arc<ServerState>) -> LicenseServerServer<Self> {
+        LicenseServerServer::new(Self { state })
+    }
+
+    async fn authenticate<T>(&self, request: &tonic::Request<T>) -> Result<Admin, tonic::Status> {
+        let Some(key) = request.metadata().get(ADMIN_KEY_METADATA) else {
+            return Err(tonic::Status::unauthenticated("admin key is missing"));
+        };
+        let Some(key) = key
+            .to_str()
+            .ok()
+            .and_then(|key| key.trim().parse::<Uuid>().ok())
+        else {
+            return Err(tonic::Status::unauthenticated("admin key is malformed"));
+        };
+
+        if self.state.root_admin_key == Some(key) {
+            return Ok(Admin::Root);
+        }
+
+        match admin_key::Entity::find_by_id(key)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?
+        {
+            Some(key) => Ok(Admin::App(key)),
+            None => {
+                tracing::info!("admin.key_rejected");
+                Err(tonic::Status::unauthenticated("admin key is not valid"))
+            }
+        }
+    }
+
+    async fn find_license(
+        &self,
+        admin: &Admin,
+        key: &str,
+    ) -> Result<license::Model, tonic::Status> {
+        self.find_license_by_id(admin, parse_key(key, "license")?)
+            .await
+    }
+
+    async fn find_license_by_id(
+        &self,
+        admin: &Admin,
+        id: Uuid,
+    ) -> Result<license::Model, tonic::Status> {
+        let Some(license) = license::Entity::find_by_id(id)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?
+        else {
+            return Err(tonic::Status::not_found("license not found"));
+        };
+        admin.check_app(&license.app)?;
+        Ok(license)
+    }
+
+    async fn find_machine(&self, admin: &Admin, id: i32) -> Result<machine::Model, tonic::Status> {
+        let Some(machine) = machine::Entity::find_by_id(id)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?
+        else {
+            return Err(tonic::Status::not_found("machine not found"));
+        };
+        self.find_license_by_id(admin, machine.license).await?;
+        Ok(machine)
+    }
+
+    /// Signs a license file valid until `not_after`, capped at the license expiry.
+    /// A file locked to a machine activates it the same way an online handshake does.
+    async fn sign_offline_license(
+        &self,
+        license: &license::Model,
+        not_after: Option<Timestamp>,
+        machine_id: &str,
+        nonce: u64,
+    ) -> Result<OfflineLicense, tonic::Status> {
+        if license.revoked_at.is_some() {
+            return Err(tonic::Status::failed_precondition("license is revoked"));
+        }
+
+        let now = Utc::now();
+        let not_after = match not_after {
+            Some(not_after) => parse_timestamp(Some(not_after), "not_after")?.min(license.expiry),
+            None => license.expiry,
+        };
+        if not_after <= now {
+            return Err(tonic::Status::failed_precondition("license is expired"));
+        }
+
+        // a file for any machine would get around the machine limit
+        self.state
+            .bind_machine(license, machine_id)
+            .await
+            .map_err(|err| match err {
+                LicenseError::MachineLimit => {
+                    tonic::Status::failed_precondition("machine limit reached")
+                }
+                LicenseError::MachineUnknown => tonic::Status::invalid_argument(
+                    "license is limited to machines, missing machine_id",
+                ),
+                _ => tonic::Status::internal("database error"),
+            })?;
+
+        let Some(app) = app::Entity::find_by_id(&license.app)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?
+        else {
+            return Err(tonic::Status::not_found("app not found"));
+        };
+        let mut key = SigningKey::try_from(app.private_key.as_slice())
+            .map_err(|_| tonic::Status::internal("app key is corrupted"))?;
+
+        let payload = offline_license::Payload {
+            key_id: license.id.to_string(),
+            license: Some(license_response(license)),
+            not_before: Some(now.to_protobuf()),
+            not_after: Some(not_after.to_protobuf()),
+            machine_id: machine_id.to_owned(),
+        };
+        Ok(OfflineLicense::sign(payload, nonce, &mut key))
+    }
+}
+
+fn db_error(err: sea_orm::DbErr) -> tonic::Status {
+    tracing::error!("admin.db_error: {err}");
+    tonic::Status::internal("database error")
+}
+
+fn parse_json(data: &str, field: &str) -> Result<serde_json::Value, tonic::Status> {
+    if data.is_empty() {
+        return Ok(serde_json::json!({}));
+    }
+    serde_json::from_str(data)
+        .map_err(|_| tonic::Status::invalid_argument(format!("{field} is not valid json")))
+}
+
+fn parse_key(key: &str, field: &str) -> Result<Uuid, tonic::Status> {
+    key.trim()
+        .parse()
+        .map_err(|_| tonic::Status::invalid_argument(format!("{field} is not a valid key")))
+}
+
+fn parse_limit(limit: Option<u64>) -> Result<Option<i32>, tonic::Status> {
+    limit
+        .map(i32::try_from)
+        .transpose()
+        .map_err(|_| tonic::Status::invalid_argument("policy limit is too large"))
+}
+
+fn parse_page_size(page_size: u32) -> u64 {
+    match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        size => u64::from(size).min(MAX_PAGE_SIZE),
+    }
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T, tonic::Status> {
+    value.ok_or_else(|| tonic::Status::invalid_argument(format!("{field} is required")))
+}
+
+fn parse_timestamp(
+    timestamp: Option<Timestamp>,
+    field: &str,
+) -> Result<DateTime<Utc>, tonic::Status> {
+    DateTime::try_from_protobuf(&required(timestamp, field)?)
+        .ok_or_else(|| tonic::Status::invalid_argument(format!("{field} is not a valid timestamp")))
+}
+
+#[tonic::async_trait]
+impl LicenseServer for AdminV1 {
+    async fn create_app(
+        &self,
+        request: tonic::Request<CreateAppReq>,
+    ) -> Result<tonic::Response<CreateAppResponse>, tonic::Status> {
+        self.authenticate(&request).await?.check_root()?;
+        let request = request.into_inner();
+
+        if request.name.is_empty() {
+            return Err(tonic::Status::invalid_argument("app name is required"));
+        }
+        let data_schema = parse_json(&request.data_schema, "data_schema")?;
+        let admin_key = (!request.admin_key.is_empty())
+            .then(|| parse_key(&request.admin_key, "admin_key"))
+            .transpose()?;
+
+        let existing = app::Entity::find_by_id(&request.name)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?;
+        if existing.is_some() {
+            return Err(tonic::Status::already_exists("app already exists"));
+        }
+
+        let signing_key = SigningKey::try_from(&rand::random::<[u8; 32]>())
+            .map_err(|_| tonic::Status::internal("key generation failed"))?;
+        let public_key = VerifyingKey(signing_key.verifying_key());
+
+        let txn = self.state.db.begin().await.map_err(db_error)?;
+
+        app::ActiveModel {
+            name: Set(request.name.clone()),
+            private_key: Set(signing_key.to_bytes().to_vec()),
+            public_key: Set(public_key.0.to_bytes().to_vec()),
+            data_schema: Set(data_schema),
+        }
+        .insert(&txn)
+        .await
+        .map_err(db_error)?;
+
+        if let Some(admin_key) = admin_key {
+            admin_key::ActiveModel {
+                id: Set(admin_key),
+                owner: Set(request.app_owner),
+                app: Set(request.name.clone()),
+            }
+            .insert(&txn)
+            .await
+            .map_err(db_error)?;
+        }
+
+        txn.commit().await.map_err(db_error)?;
+
+        tracing::info!("admin.app_created: {}", request.name);
+
+        Ok(tonic::Response::new(CreateAppResponse {
+            id: request.name,
+            public_key: public_key.to_string(),
+        }))
+    }
+
+    async fn create_license(
+        &self,
+        request: tonic::Request<CreateLicenseReq>,
+    ) -> Result<tonic::Response<CreateLicenseResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        admin.check_app(&request.app)?;
+
+        let expiry = parse_timestamp(request.expiry, "expiry")?;
+        let extra_data = parse_json(&request.extra_data, "extra_data")?;
+        let policy = request.policy.unwrap_or_default();
+        let limit_connections = parse_limit(policy.limit_connections)?;
+        let limit_machines = parse_limit(policy.limit_machines)?;
+
+        let app = app::Entity::find_by_id(&request.app)
+            .one(&self.state.db)
+            .await
+            .map_err(db_error)?;
+        if app.is_none() {
+            return Err(tonic::Status::not_found("app not found"));
+        }
+
+        let license = license::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            holder: Set(request.holder),
+            expiry: Set(expiry),
+            extra_data: Set(extra_data),
+            policy_limit_connections: Set(limit_connections),
+            policy_limit_machines: Set(limit_machines),
+            app: Set(request.app),
+            ..Default::default()
+        }
+        .insert(&self.state.db)
+        .await
+        .map_err(db_error)?;
+
+        tracing::info!("admin.license_created: {}", license.id);
+
+        Ok(tonic::Response::new(CreateLicenseResponse {
+            license_key: license.id.to_string(),
+        }))
+    }
+
+    async fn extend_license(
+        &self,
+        request: tonic::Request<ExtendLicense>,
+    ) -> Result<tonic::Response<ExtendLicenseResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let license = self.find_license(&admin, &request.license).await?;
+        let to_date = parse_timestamp(request.to_date, "to_date")?;
+        let from_date = license.expiry;
+
+        let txn = self.state.db.begin().await.map_err(db_error)?;
+
+        let mut active = license.into_active_model();
+        active.expiry = Set(to_date);
+        let license = active.update(&txn).await.map_err(db_error)?;
+
+        audit::record(
+            &txn,
+            license.id,
+            EventKind::LicenseExtended,
+            json!({ "from": from_date, "to": to_date, "by": admin.name() }),
+        )
+        .await
+        .map_err(db_error)?;
+
+        txn.commit().await.map_err(db_error)?;
+
+        tracing::info!("admin.license_extended: {}", license.id);
+        self.state.invalidate_license(license.id);
+
+        Ok(tonic::Response::new(ExtendLicenseResponse {}))
+    }
+
+    async fn revoke_license(
+        &self,
+        request: tonic::Request<RevokeLicenseReq>,
+    ) -> Result<tonic::Response<RevokeLicenseResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let license = self.find_license(&admin, &request.license).await?;
+        if license.revoked_at.is_some() {
+            return Err(tonic::Status::failed_precondition(
+                "license is already revoked",
+            ));
+        }
+
+        let txn = self.state.db.begin().await.map_err(db_error)?;
+
+        let revoked_at = Utc::now();
+        let mut active = license.into_active_model();
+        active.revoked_at = Set(Some(revoked_at));
+        active.revoke_reason = Set(Some(request.reason.clone()));
+        let license = active.update(&txn).await.map_err(db_error)?;
+
+        audit::record(
+            &txn,
+            license.id,
+            EventKind::LicenseRevoked,
+            json!({ "reason": request.reason, "at": revoked_at, "by": admin.name() }),
+        )
+        .await
+        .map_err(db_error)?;
+
+        txn.commit().await.map_err(db_error)?;
+
+        tracing::info!("admin.license_revoked: {}", license.id);
+        self.state.invalidate_license(license.id);
+
+        Ok(tonic::Response::new(RevokeLicenseResponse {}))
+    }
+
+    async fn list_license_events(
+        &self,
+        request: tonic::Request<ListLicenseEventsReq>,
+    ) -> Result<tonic::Response<ListLicenseEventsResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let app = admin.scope_app(request.app)?;
+        let license = match request.license {
+            Some(key) => Some(self.find_license(&admin, &key).await?.id),
+            None => None,
+        };
+        let since = request
+            .since
+            .map(|since| parse_timestamp(Some(since), "since"))
+            .transpose()?;
+        let until = request
+            .until
+            .map(|until| parse_timestamp(Some(until), "until"))
+            .transpose()?;
+        let cursor = match request.cursor.as_str() {
+            "" => None,
+            cursor => Some(
+                cursor
+                    .parse::<i32>()
+                    .map_err(|_| tonic::Status::invalid_argument("cursor is not valid"))?,
+            ),
+        };
+        let page_size = parse_page_size(request.page_size);
+
+        let mut query = license_log::Entity::find();
+        if let Some(app) = app {
+            query = query
+                .inner_join(license::Entity)
+                .filter(license::Column::App.eq(app));
+        }
+        if let Some(license) = license {
+            query = query.filter(license_log::Column::License.eq(license));
+        }
+        if let Some(kind) = request.kind {
+            query = query.filter(license_log::Column::Kind.eq(kind));
+        }
+        if let Some(since) = since {
+            query = query.filter(license_log::Column::Timestamp.gte(since));
+        }
+        if let Some(until) = until {
+            query = query.filter(license_log::Column::Timestamp.lt(until));
+        }
+        if let Some(cursor) = cursor {
+            query = query.filter(license_log::Column::Id.gt(cursor));
+        }
+
+        // one extra row tells whether there is a next page
+        let mut events = query
+            .order_by_asc(license_log::Column::Id)
+            .limit(page_size + 1)
+            .all(&self.state.db)
+            .await
+            .map_err(db_error)?;
+
+        let next_cursor = if events.len() as u64 > page_size {
+            events.truncate(page_size as usize);
+            events
+                .last()
+                .map(|event| event.id.to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let events = events
+            .into_iter()
+            .map(|event| LicenseEvent {
+                id: event.id,
+                license: event.license.to_string(),
+                kind: event.kind,
+                data: event.data.to_string(),
+                timestamp: Some(event.timestamp.to_protobuf()),
+            })
+            .collect();
+
+        Ok(tonic::Response::new(ListLicenseEventsResponse {
+            events,
+            next_cursor,
+        }))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: tonic::Request<ListSessionsReq>,
+    ) -> Result<tonic::Response<ListSessionsResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let app = admin.scope_app(request.app)?;
+        let license = match request.license {
+            Some(key) => Some(self.find_license(&admin, &key).await?.id),
+            None => None,
+        };
+
+        let mut query = self.state.active_sessions();
+        if let Some(app) = app {
+            query = query
+                .inner_join(license::Entity)
+                .filter(license::Column::App.eq(app));
+        }
+        if let Some(license) = license {
+            query = query.filter(session::Column::License.eq(license));
+        }
+
+        let sessions = query
+            .order_by_asc(session::Column::StartedAt)
+            .all(&self.state.db)
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .map(|session| SessionInfo {
+                id: session.id.to_string(),
+                license: session.license.to_string(),
+                peer: session.peer.unwrap_or_default(),
+                started_at: Some(session.started_at.to_protobuf()),
+                last_seen: Some(session.last_seen.to_protobuf()),
+            })
+            .collect();
+
+        Ok(tonic::Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn terminate_sessions(
+        &self,
+        request: tonic::Request<TerminateSessionsReq>,
+    ) -> Result<tonic::Response<TerminateSessionsResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let sessions = match required(request.target, "target")? {
+            Target::Session(id) => {
+                let id = parse_key(&id, "session")?;
+                let Some(session) = session::Entity::find_by_id(id)
+                    .one(&self.state.db)
+                    .await
+                    .map_err(db_error)?
+                else {
+                    return Err(tonic::Status::not_found("session not found"));
+                };
+                self.find_license_by_id(&admin, session.license).await?;
+                vec![session]
+            }
+            Target::License(key) => {
+                let license = self.find_license(&admin, &key).await?;
+                session::Entity::find()
+                    .filter(session::Column::License.eq(license.id))
+                    .all(&self.state.db)
+                    .await
+                    .map_err(db_error)?
+            }
+        };
+
+        let ids: Vec<_> = sessions.iter().map(|session| session.id).collect();
+        let terminated = self
+            .state
+            .terminate_sessions(&ids)
+            .await
+            .map_err(db_error)?;
+
+        for session in sessions {
+            self.state
+                .log_event(
+                    session.license,
+                    EventKind::SessionTerminated,
+                    json!({ "session": session.id, "peer": session.peer, "by": admin.name() }),
+                )
+                .await;
+        }
+
+        Ok(tonic::Response::new(TerminateSessionsResponse {
+            terminated,
+        }))
+    }
+
+    async fn list_machines(
+        &self,
+        request: tonic::Request<ListMachinesReq>,
+    ) -> Result<tonic::Response<ListMachinesResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let license = self.find_license(&admin, &request.license).await?;
+
+        let machines = machine::Entity::find()
+            .filter(machine::Column::License.eq(license.id))
+            .order_by_asc(machine::Column::ActivatedAt)
+            .all(&self.state.db)
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .map(|machine| MachineInfo {
+                id: machine.id,
+                fingerprint: machine.fingerprint,
+                label: machine.label,
+                activated_at: Some(machine.activated_at.to_protobuf()),
+                last_seen: Some(machine.last_seen.to_protobuf()),
+            })
+            .collect();
+
+        Ok(tonic::Response::new(ListMachinesResponse { machines }))
+    }
+
+    async fn label_machine(
+        &self,
+        request: tonic::Request<LabelMachineReq>,
+    ) -> Result<tonic::Response<LabelMachineResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let machine = self.find_machine(&admin, request.machine).await?;
+
+        let mut machine = machine.into_active_model();
+        machine.label = Set(request.label.filter(|label| !label.is_empty()));
+        machine.update(&self.state.db).await.map_err(db_error)?;
+
+        Ok(tonic::Response::new(LabelMachineResponse {}))
+    }
+
+    async fn deactivate_machine(
+        &self,
+        request: tonic::Request<DeactivateMachineReq>,
+    ) -> Result<tonic::Response<DeactivateMachineResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let machine = self.find_machine(&admin, request.machine).await?;
+
+        let txn = self.state.db.begin().await.map_err(db_error)?;
+        machine::Entity::delete_by_id(machine.id)
+            .exec(&txn)
+            .await
+            .map_err(db_error)?;
+        let ended = sessions::end_machine_sessions(&txn, machine.license, &machine.fingerprint)
+            .await
+            .map_err(db_error)?;
+        audit::record(
+            &txn,
+            machine.license,
+            EventKind::MachineDeactivated,
+            json!({ "machine": machine.fingerprint, "label": machine.label, "by": admin.name() }),
+        )
+        .await
+        .map_err(db_error)?;
+        txn.commit().await.map_err(db_error)?;
+        self.state.notify_terminated(&ended);
+
+        tracing::info!("admin.machine_deactivated: {}", machine.id);
+
+        Ok(tonic::Response::new(DeactivateMachineResponse {}))
+    }
+
+    async fn issue_offline_license(
+        &self,
+        request: tonic::Request<IssueOfflineLicenseReq>,
+    ) -> Result<tonic::Response<IssueOfflineLicenseResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let license = self.find_license(&admin, &request.license).await?;
+        let machine_id = request.machine_id.unwrap_or_default();
+        let file = self
+            .sign_offline_license(&license, request.not_after, &machine_id, rand::random())
+            .await?;
+
+        self.state
+            .log_event(
+                license.id,
+                EventKind::OfflineIssued,
+                json!({ "machine": machine_id, "by": admin.name() }),
+            )
+            .await;
+        tracing::info!("admin.offline_issued: {}", license.id);
+
+        Ok(tonic::Response::new(IssueOfflineLicenseResponse {
+            license_file: file.encode_to_vec(),
+        }))
+    }
+
+    async fn activate_offline(
+        &self,
+        request: tonic::Request<ActivateOfflineReq>,
+    ) -> Result<tonic::Response<ActivateOfflineResponse>, tonic::Status> {
+        let admin = self.authenticate(&request).await?;
+        let request = request.into_inner();
+
+        let activation = ActivationRequest::decode(request.request_file.as_slice())
+            .map_err(|_| tonic::Status::invalid_argument("request_file is malformed"))?;
+        if activation.machine_id.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "request_file has no machine fingerprint",
+            ));
+        }
+
+        let license = self.find_license(&admin, &activation.key_id).await?;
+        // signed over the client's nonce, so the response only imports where it was requested
+        let file = self
+            .sign_offline_license(
+                &license,
+                request.not_after,
+                &activation.machine_id,
+                activation.nonce,
+            )
+            .await?;
+
+        self.state
+            .log_event(
+                license.id,
+                EventKind::OfflineActivated,
+                json!({ "machine": activation.machine_id, "by": admin.name() }),
+            )
+            .await;
+        tracing::info!("admin.offline_activated: {}", license.id);
+
+        Ok(tonic::Response::new(ActivateOfflineResponse {
+            response_file: file.encode_to_vec(),
+        }))
+    }
+}
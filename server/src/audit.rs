@@ -0,0 +1,69 @@
+use chrono::Utc;
+use sea_orm::{prelude::Uuid, ActiveModelTrait, ConnectionTrait, DbErr, Set};
+
+use crate::{entities::license_log, ServerState};
+
+/// Kind of a `license_log` entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    LicenseExtended,
+    LicenseRevoked,
+    HandshakeAccepted,
+    HandshakeDenied,
+    SessionLimit,
+    HeartbeatDenied,
+    SessionClosed,
+    SessionTerminated,
+    MachineLimit,
+    MachineReleased,
+    MachineDeactivated,
+    OfflineIssued,
+    OfflineActivated,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::LicenseExtended => "license.extended",
+            EventKind::LicenseRevoked => "license.revoked",
+            EventKind::HandshakeAccepted => "handshake.accepted",
+            EventKind::HandshakeDenied => "handshake.denied",
+            EventKind::SessionLimit => "session.limit",
+            EventKind::HeartbeatDenied => "heartbeat.denied",
+            EventKind::SessionClosed => "session.closed",
+            EventKind::SessionTerminated => "session.terminated",
+            EventKind::MachineLimit => "machine.limit",
+            EventKind::MachineReleased => "machine.released",
+            EventKind::MachineDeactivated => "machine.deactivated",
+            EventKind::OfflineIssued => "offline.issued",
+            EventKind::OfflineActivated => "offline.activated",
+        }
+    }
+}
+
+pub async fn record(
+    db: &impl ConnectionTrait,
+    license: Uuid,
+    kind: EventKind,
+    data: serde_json::Value,
+) -> Result<(), DbErr> {
+    license_log::ActiveModel {
+        kind: Set(kind.as_str().to_owned()),
+        license: Set(license),
+        data: Set(data),
+        timestamp: Set(Utc::now()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(())
+}
+
+impl ServerState {
+    /// Records a connection event; failures are only traced so they never break a session
+    pub(crate) async fn log_event(&self, license: Uuid, kind: EventKind, data: serde_json::Value) {
+        if let Err(err) = record(&self.db, license, kind, data).await {
+            tracing::error!("audit.record_failed: {err}");
+        }
+    }
+}
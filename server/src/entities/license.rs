@@ -13,6 +13,9 @@ pub struct Model {
     pub extra_data: Json,
     pub policy_limit_connections: Option<i32>,
     pub app: String,
+    pub revoked_at: Option<DateTimeUtc>,
+    pub revoke_reason: Option<String>,
+    pub policy_limit_machines: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -27,6 +30,10 @@ pub enum Relation {
     App,
     #[sea_orm(has_many = "super::license_log::Entity")]
     LicenseLog,
+    #[sea_orm(has_many = "super::machine::Entity")]
+    Machine,
+    #[sea_orm(has_many = "super::session::Entity")]
+    Session,
 }
 
 impl Related<super::app::Entity> for Entity {
@@ -41,4 +48,16 @@ impl Related<super::license_log::Entity> for Entity {
     }
 }
 
+impl Related<super::machine::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Machine.def()
+    }
+}
+
+impl Related<super::session::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Session.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
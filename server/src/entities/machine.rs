@@ -0,0 +1,33 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "machine")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub license: Uuid,
+    pub fingerprint: String,
+    pub label: Option<String>,
+    pub activated_at: DateTimeUtc,
+    pub last_seen: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::license::Entity",
+        from = "Column::License",
+        to = "super::license::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    License,
+}
+
+impl Related<super::license::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::License.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
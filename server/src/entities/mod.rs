@@ -6,3 +6,5 @@ pub mod admin_key;
 pub mod app;
 pub mod license;
 pub mod license_log;
+pub mod machine;
+pub mod session;
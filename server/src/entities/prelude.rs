@@ -4,3 +4,5 @@ pub use super::admin_key::Entity as AdminKey;
 pub use super::app::Entity as App;
 pub use super::license::Entity as License;
 pub use super::license_log::Entity as LicenseLog;
+pub use super::machine::Entity as Machine;
+pub use super::session::Entity as Session;
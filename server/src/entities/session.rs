@@ -0,0 +1,34 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[sea_orm(table_name = "session")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub license: Uuid,
+    pub peer: Option<String>,
+    pub started_at: DateTimeUtc,
+    pub last_seen: DateTimeUtc,
+    /// fingerprint the session was opened from, if the client sent one
+    pub machine: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::license::Entity",
+        from = "Column::License",
+        to = "super::license::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    License,
+}
+
+impl Related<super::license::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::License.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
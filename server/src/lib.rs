@@ -1,50 +1,76 @@
-use std::collections::HashMap;
+#![allow(clippy::result_large_err)] // tonic::Status is the error type of every handler
 
+use cached::{Cached, TimedSizedCache};
 use migration::MigratorTrait;
-use sea_orm::{prelude::Uuid, Database, DatabaseConnection};
+use sea_orm::{prelude::Uuid, Database, DatabaseConnection, DbErr, EntityTrait};
 use serde::Deserialize;
-use tokio::sync::Mutex;
 
-mod entities;
+use entities::license;
+
+mod audit;
+pub mod entities;
+mod machines;
+mod sessions;
+
+const LICENSE_CACHE_SIZE: usize = 10_000;
+/// Upper bound on how long a change made by another server instance stays unnoticed
+const LICENSE_CACHE_LIFESPAN: std::time::Duration = proto::software::v1::PING_PERIOD;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub database_uri: String,
+    /// Key allowed to perform any admin call, including app creation
+    #[serde(default)]
+    pub root_admin_key: Option<Uuid>,
 }
 
-type ConnectionsTable = Mutex<HashMap<Uuid, i32>>;
-
 pub struct ServerState {
     db: DatabaseConnection,
-    connections: ConnectionsTable,
+    root_admin_key: Option<Uuid>,
+    licenses: std::sync::Mutex<TimedSizedCache<Uuid, license::Model>>,
+    session_controls: sessions::SessionControls,
 }
 
 impl ServerState {
-    async fn inc_conn(&self, id: Uuid) {
-        let mut conns_lock = self.connections.lock().await;
-        *conns_lock.entry(id).or_default() += 1;
+    /// Cached license lookup, used on every handshake and heartbeat
+    async fn license(&self, id: Uuid) -> Result<Option<license::Model>, DbErr> {
+        if let Some(license) = self.licenses.lock().unwrap().cache_get(&id) {
+            return Ok(Some(license.clone()));
+        }
+
+        let license = license::Entity::find_by_id(id).one(&self.db).await?;
+        if let Some(license) = &license {
+            self.licenses.lock().unwrap().cache_set(id, license.clone());
+        }
+        Ok(license)
     }
-    async fn dec_conn(&self, id: Uuid) {
-        let mut conns_lock = self.connections.lock().await;
-        conns_lock.entry(id).and_modify(|v| {
-            if *v > 0 {
-                *v -= 1
-            }
-        });
+
+    /// Must be called after every write to a license so live sessions see it
+    fn invalidate_license(&self, id: Uuid) {
+        self.licenses.lock().unwrap().cache_remove(&id);
     }
 
     pub async fn new(config: Config) -> eyre::Result<ServerState> {
-        tracing_subscriber::fmt::init();
-
         let connection = Database::connect(config.database_uri).await?;
 
         migration::Migrator::up(&connection, None).await?;
 
-        Ok(Self {
+        let state = Self {
             db: connection,
-            connections: Mutex::new(HashMap::new()),
-        })
+            root_admin_key: config.root_admin_key,
+            licenses: std::sync::Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                LICENSE_CACHE_SIZE,
+                LICENSE_CACHE_LIFESPAN.as_secs(),
+            )),
+            session_controls: Default::default(),
+        };
+
+        let reaped = state.reap_stale_sessions().await?;
+        tracing::info!("server.sessions_reaped: {reaped}");
+
+        Ok(state)
     }
 }
 
+pub mod admin_v1_server;
 pub mod v1_server;
@@ -0,0 +1,121 @@
+use chrono::Utc;
+use proto::software::v1::LicenseError;
+use sea_orm::{
+    prelude::Uuid, ActiveModelTrait, ColumnTrait, DatabaseTransaction, DbErr, EntityTrait,
+    IntoActiveModel, PaginatorTrait, QueryFilter, QuerySelect, Set, TransactionTrait,
+};
+
+use crate::{
+    entities::{license, machine},
+    sessions, ServerState,
+};
+
+impl ServerState {
+    /// Activates `fingerprint` for the license on its own, outside of a handshake
+    pub(crate) async fn bind_machine(
+        &self,
+        license: &license::Model,
+        fingerprint: &str,
+    ) -> Result<(), LicenseError> {
+        self.try_bind_machine(license, fingerprint)
+            .await
+            .map_err(|err| {
+                tracing::error!("machine.bind_failed: {err}");
+                LicenseError::Internal
+            })?
+    }
+
+    /// Removes the activation of `fingerprint` and ends the sessions opened from it,
+    /// returns whether there was one
+    pub(crate) async fn release_machine(
+        &self,
+        license: Uuid,
+        fingerprint: &str,
+    ) -> Result<bool, DbErr> {
+        let txn = self.db.begin().await?;
+        let result = machine::Entity::delete_many()
+            .filter(machine::Column::License.eq(license))
+            .filter(machine::Column::Fingerprint.eq(fingerprint))
+            .exec(&txn)
+            .await?;
+        let ended = sessions::end_machine_sessions(&txn, license, fingerprint).await?;
+        txn.commit().await?;
+
+        self.notify_terminated(&ended);
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn try_bind_machine(
+        &self,
+        license: &license::Model,
+        fingerprint: &str,
+    ) -> Result<Result<(), LicenseError>, DbErr> {
+        let txn = self.db.begin().await?;
+
+        // serializes activations for this license across instances
+        license::Entity::find_by_id(license.id)
+            .lock_exclusive()
+            .one(&txn)
+            .await?;
+
+        let bound = bind_machine(&txn, license, fingerprint).await?;
+        if bound.is_ok() {
+            txn.commit().await?;
+        }
+        Ok(bound)
+    }
+}
+
+/// Activates `fingerprint` for the license, or refreshes it if it was activated before.
+/// Clients that send no fingerprint are only let through on licenses without a machine limit.
+/// Callers lock the license row, so activations don't race past the limit.
+pub(crate) async fn bind_machine(
+    txn: &DatabaseTransaction,
+    license: &license::Model,
+    fingerprint: &str,
+) -> Result<Result<(), LicenseError>, DbErr> {
+    if fingerprint.is_empty() {
+        return Ok(match license.policy_limit_machines {
+            Some(_) => Err(LicenseError::MachineUnknown),
+            None => Ok(()),
+        });
+    }
+
+    let now = Utc::now();
+    let known = machine::Entity::find()
+        .filter(machine::Column::License.eq(license.id))
+        .filter(machine::Column::Fingerprint.eq(fingerprint))
+        .one(txn)
+        .await?;
+
+    match known {
+        Some(machine) => {
+            let mut active = machine.into_active_model();
+            active.last_seen = Set(now);
+            active.update(txn).await?;
+        }
+        None => {
+            if let Some(limit) = license.policy_limit_machines {
+                let activated = machine::Entity::find()
+                    .filter(machine::Column::License.eq(license.id))
+                    .count(txn)
+                    .await?;
+                if activated + 1 > limit.max(0) as u64 {
+                    return Ok(Err(LicenseError::MachineLimit));
+                }
+            }
+
+            machine::ActiveModel {
+                license: Set(license.id),
+                fingerprint: Set(fingerprint.to_owned()),
+                label: Set(None),
+                activated_at: Set(now),
+                last_seen: Set(now),
+                ..Default::default()
+            }
+            .insert(txn)
+            .await?;
+        }
+    }
+    Ok(Ok(()))
+}
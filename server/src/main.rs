@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::Arc};
 
 use figment::{
     providers::{Env, Format, Toml},
@@ -18,17 +18,21 @@ pub struct Config {
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
     let config: Config = Figment::new()
         .merge(Env::raw())
         .merge(Toml::file(CONFIG_PATH))
         .extract()?;
 
-    let server_state = ServerState::new(config.server_config).await?;
+    let server_state = Arc::new(ServerState::new(config.server_config).await?);
 
-    let server = server::v1_server::SoftwareV1::new(server_state);
+    let server = server::v1_server::SoftwareV1::new(server_state.clone());
+    let admin_server = server::admin_v1_server::AdminV1::new(server_state);
 
     tonic::transport::Server::builder()
         .add_service(server)
+        .add_service(admin_server)
         .serve((config.socket_addr, v1::PORT).into())
         .await?;
 
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use chrono::Utc;
+use proto::software::v1::{self, LicenseError};
+use sea_orm::{
+    prelude::Uuid, ActiveModelTrait, ColumnTrait, DatabaseTransaction, DbErr, EntityTrait,
+    PaginatorTrait, QueryFilter, QuerySelect, Set, TransactionTrait,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    entities::{license, session},
+    machines, ServerState,
+};
+
+/// A session that hasn't been seen for this long is considered dead
+pub const SESSION_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(v1::PING_PERIOD.as_secs() + v1::PING_GRACE.as_secs());
+
+fn stale_before() -> chrono::DateTime<Utc> {
+    Utc::now() - SESSION_TIMEOUT
+}
+
+/// Termination signals of the sessions served by this instance
+pub(crate) type SessionControls = std::sync::Mutex<HashMap<Uuid, oneshot::Sender<()>>>;
+
+/// A taken connection slot, given back on drop (including panics and early returns)
+pub(crate) struct Seat {
+    state: Arc<ServerState>,
+    pub id: Uuid,
+    /// session whose seat this one took over, see [`ServerState::reserve_seat`]
+    pub resumed: Option<Uuid>,
+    terminated: oneshot::Receiver<()>,
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        self.state.session_controls.lock().unwrap().remove(&self.id);
+
+        let state = self.state.clone();
+        let id = self.id;
+        // without a runtime the row is left for the stale session reaper
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(err) = session::Entity::delete_by_id(id).exec(&state.db).await {
+                    tracing::error!("session.release_failed: {err}");
+                }
+            });
+        }
+    }
+}
+
+impl Seat {
+    /// Resolves once an admin terminates this session
+    pub async fn terminated(&mut self) {
+        if (&mut self.terminated).await.is_err() {
+            // sender is only dropped together with the seat
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Gives the seat back now instead of on drop, returns false if it was already gone
+    pub async fn release(&self) -> Result<bool, DbErr> {
+        let result = session::Entity::delete_by_id(self.id)
+            .exec(&self.state.db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+
+    /// Marks the session alive, returns false if it no longer exists
+    pub async fn touch(&self) -> Result<bool, DbErr> {
+        let result = session::Entity::update_many()
+            .col_expr(session::Column::LastSeen, Utc::now().into())
+            .filter(session::Column::Id.eq(self.id))
+            .exec(&self.state.db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+}
+
+impl ServerState {
+    /// Checks the connection limit and takes a seat in one write transaction,
+    /// so concurrent handshakes (on any server instance) can't all pass the check.
+    /// The machine is activated in the same transaction, so a refused seat doesn't use up
+    /// a machine slot. A still open session in `resumes` is ended and gives its seat to the new one.
+    pub(crate) async fn reserve_seat(
+        self: &Arc<Self>,
+        license: &license::Model,
+        peer: Option<SocketAddr>,
+        machine_id: &str,
+        resumes: Option<Uuid>,
+    ) -> Result<Seat, LicenseError> {
+        self.try_reserve_seat(license, peer, machine_id, resumes)
+            .await
+            .map_err(|err| {
+                tracing::error!("session.reserve_failed: {err}");
+                LicenseError::Internal
+            })?
+    }
+
+    async fn try_reserve_seat(
+        self: &Arc<Self>,
+        license: &license::Model,
+        peer: Option<SocketAddr>,
+        machine_id: &str,
+        resumes: Option<Uuid>,
+    ) -> Result<Result<Seat, LicenseError>, DbErr> {
+        let txn = self.db.begin().await?;
+
+        // Writing first makes SQLite take its database-wide write lock now, waiting out
+        // other instances' reservations, rather than upgrading a read lock later, which
+        // fails with SQLITE_BUSY under contention. SQLite ignores `lock_exclusive`,
+        // it serializes reservations for this license on backends with row locks.
+        session::Entity::delete_many()
+            .filter(session::Column::License.eq(license.id))
+            .filter(session::Column::LastSeen.lt(stale_before()))
+            .exec(&txn)
+            .await?;
+
+        license::Entity::find_by_id(license.id)
+            .lock_exclusive()
+            .one(&txn)
+            .await?;
+
+        let mut resumed = None;
+        if let Some(old) = resumes {
+            let result = session::Entity::delete_many()
+                .filter(session::Column::Id.eq(old))
+                .filter(session::Column::License.eq(license.id))
+                .exec(&txn)
+                .await?;
+            resumed = (result.rows_affected > 0).then_some(old);
+        }
+
+        if let Some(limit) = license.policy_limit_connections {
+            let active = session::Entity::find()
+                .filter(session::Column::License.eq(license.id))
+                .count(&txn)
+                .await?;
+            if active + 1 > limit.max(0) as u64 {
+                return Ok(Err(LicenseError::TooManySessions));
+            }
+        }
+
+        if let Err(err) = machines::bind_machine(&txn, license, machine_id).await? {
+            return Ok(Err(err));
+        }
+
+        let now = Utc::now();
+        let session = session::ActiveModel {
+            id: Set(Uuid::new_v4()),
+            license: Set(license.id),
+            peer: Set(peer.map(|peer| peer.to_string())),
+            started_at: Set(now),
+            last_seen: Set(now),
+            machine: Set((!machine_id.is_empty()).then(|| machine_id.to_owned())),
+        }
+        .insert(&txn)
+        .await?;
+
+        txn.commit().await?;
+
+        let (terminate, terminated) = oneshot::channel();
+        let mut controls = self.session_controls.lock().unwrap();
+        // an old session served elsewhere finds out on its next heartbeat
+        if let Some(terminate) = resumed.and_then(|old| controls.remove(&old)) {
+            let _ = terminate.send(());
+        }
+        controls.insert(session.id, terminate);
+
+        Ok(Ok(Seat {
+            state: self.clone(),
+            id: session.id,
+            resumed,
+            terminated,
+        }))
+    }
+
+    /// Ends the given sessions. Ones served by this instance are notified right away,
+    /// the rest find out on their next heartbeat.
+    pub(crate) async fn terminate_sessions(&self, ids: &[Uuid]) -> Result<u64, DbErr> {
+        let result = session::Entity::delete_many()
+            .filter(session::Column::Id.is_in(ids.iter().copied()))
+            .exec(&self.db)
+            .await?;
+
+        self.notify_terminated(ids);
+        Ok(result.rows_affected)
+    }
+
+    /// Tells the sessions served by this instance that they were ended
+    pub(crate) fn notify_terminated(&self, ids: &[Uuid]) {
+        let mut controls = self.session_controls.lock().unwrap();
+        for id in ids {
+            if let Some(terminate) = controls.remove(id) {
+                let _ = terminate.send(());
+            }
+        }
+    }
+
+    /// Sessions seen within [`SESSION_TIMEOUT`]
+    pub(crate) fn active_sessions(&self) -> sea_orm::Select<session::Entity> {
+        session::Entity::find().filter(session::Column::LastSeen.gte(stale_before()))
+    }
+
+    /// Drops sessions left behind by crashed or unreachable server instances
+    pub(crate) async fn reap_stale_sessions(&self) -> Result<u64, DbErr> {
+        let result = session::Entity::delete_many()
+            .filter(session::Column::LastSeen.lt(stale_before()))
+            .exec(&self.db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+}
+
+/// Ends the sessions opened from `fingerprint` within `txn`.
+/// Pass the returned ids to [`ServerState::notify_terminated`] once it commits.
+pub(crate) async fn end_machine_sessions(
+    txn: &DatabaseTransaction,
+    license: Uuid,
+    fingerprint: &str,
+) -> Result<Vec<Uuid>, DbErr> {
+    let ids = session::Entity::find()
+        .select_only()
+        .column(session::Column::Id)
+        .filter(session::Column::License.eq(license))
+        .filter(session::Column::Machine.eq(fingerprint))
+        .into_tuple()
+        .all(txn)
+        .await?;
+
+    session::Entity::delete_many()
+        .filter(session::Column::Id.is_in(ids.iter().copied()))
+        .exec(txn)
+        .await?;
+    Ok(ids)
+}
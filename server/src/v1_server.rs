@@ -1,25 +1,44 @@
 use std::sync::Arc;
 
-use proto::software::v1::{self, authority_server::AuthorityServer, ServerMessage};
+use chrono::Utc;
+use proto::{
+    software::v1::{
+        self, authority_server::AuthorityServer, info_response, ReleaseMachineRequest,
+        ReleaseMachineResponse, ServerMessage,
+    },
+    ChronoExt,
+};
+use sea_orm::prelude::Uuid;
+use serde_json::json;
 
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::ServerState;
+use crate::{audit::EventKind, entities::license, ServerState};
 
 const CHANNEL_BUFFER: usize = 100;
 
 mod connection;
 
+/// License details handed to a client that passed the license check
+pub(crate) fn license_response(license: &license::Model) -> info_response::Response {
+    info_response::Response {
+        expiry: Some(license.expiry.to_protobuf()),
+        extra_data: license.extra_data.to_string(),
+        issued_at: Some(Utc::now().to_protobuf()),
+        holder: license.holder.clone(),
+        // set by the connection, which knows the session
+        resumption_token: Vec::new(),
+    }
+}
+
 pub struct SoftwareV1 {
     state: Arc<ServerState>,
 }
 
 impl SoftwareV1 {
-    pub fn new(state: ServerState) -> AuthorityServer<Self> {
-        AuthorityServer::new(Self {
-            state: Arc::new(state),
-        })
+    pub fn new(state: Arc<ServerState>) -> AuthorityServer<Self> {
+        AuthorityServer::new(Self { state })
     }
 }
 
@@ -31,12 +50,57 @@ impl v1::authority_server::Authority for SoftwareV1 {
         &self,
         request: tonic::Request<tonic::Streaming<v1::ClientMessage>>,
     ) -> std::result::Result<tonic::Response<Self::HearthbeatStream>, tonic::Status> {
+        let addr = request.remote_addr();
         let stream = request.into_inner();
 
         let (server_tx, server_rx) = mpsc::channel(CHANNEL_BUFFER);
 
-        tokio::task::spawn(connection::handle(self.state.clone(), server_tx, stream));
+        tokio::task::spawn(connection::handle(
+            self.state.clone(),
+            server_tx,
+            stream,
+            addr,
+        ));
 
         Ok(tonic::Response::new(ReceiverStream::new(server_rx)))
     }
+
+    async fn release_machine(
+        &self,
+        request: tonic::Request<ReleaseMachineRequest>,
+    ) -> std::result::Result<tonic::Response<ReleaseMachineResponse>, tonic::Status> {
+        let request = request.into_inner();
+
+        let Ok(key) = request.key_id.trim().parse::<Uuid>() else {
+            return Err(tonic::Status::invalid_argument("key is not valid"));
+        };
+        let license = self.state.license(key).await.map_err(|err| {
+            tracing::error!("machine.release_failed: {err}");
+            tonic::Status::internal("database error")
+        })?;
+        let Some(license) = license else {
+            return Err(tonic::Status::not_found("license not found"));
+        };
+
+        let released = self
+            .state
+            .release_machine(license.id, &request.machine_id)
+            .await
+            .map_err(|err| {
+                tracing::error!("machine.release_failed: {err}");
+                tonic::Status::internal("database error")
+            })?;
+
+        if released {
+            self.state
+                .log_event(
+                    license.id,
+                    EventKind::MachineReleased,
+                    json!({ "machine": request.machine_id }),
+                )
+                .await;
+        }
+
+        Ok(tonic::Response::new(ReleaseMachineResponse { released }))
+    }
 }
@@ -1,71 +1,187 @@
 use chrono::{DateTime, Utc};
-use migration::ExprTrait;
+use prost::Message;
 use proto::{
     software::v1::{
-        client_message, info_request, info_response, server_message, InfoResponse, LicenseError,
-        ServerHearthbeat, ServerHearthbeatData, SigningKey,
+        client_message, info_request, info_response, resumption_token, server_message,
+        InfoResponse, LicenseError, ResumptionToken, ServerGoodbye, ServerGoodbyeData,
+        ServerHearthbeat, ServerHearthbeatData, SigningKey, VerifyingKey,
     },
     ChronoExt,
 };
-use sea_orm::{prelude::Uuid, ActiveModelTrait, EntityTrait, Set};
+use sea_orm::{prelude::Uuid, ColumnTrait, EntityTrait, QueryFilter};
 use serde_json::json;
 use tokio::sync::mpsc;
 
 use crate::{
-    entities::{self, app, license},
+    audit::EventKind,
+    entities::{self, license},
+    sessions::{Seat, SESSION_TIMEOUT},
     ServerState,
 };
 
 use super::v1;
-use std::{str::FromStr, sync::Arc};
-use v1::{ClientMessage, ServerMessage};
+use std::{net::SocketAddr, sync::Arc};
+use v1::ServerMessage;
 
 type ServerTX = mpsc::Sender<Result<ServerMessage, tonic::Status>>;
 type ServerRX = tonic::Streaming<v1::ClientMessage>;
 
+/// Who is on the other end of a connection, attached to every logged event
+#[derive(Clone, Copy)]
+pub struct PeerInfo {
+    pub addr: Option<SocketAddr>,
+    /// nonce of the client's auth request
+    pub nonce: u64,
+}
+
+impl PeerInfo {
+    fn event(&self, mut data: serde_json::Value) -> serde_json::Value {
+        data["peer"] = json!(self.addr.map(|addr| addr.to_string()));
+        data["nonce"] = json!(self.nonce);
+        data
+    }
+}
+
 pub struct ConnectionData {
     signing_key: SigningKey,
     license: license::Model,
+    peer: PeerInfo,
+    seat: Seat,
 }
 
 pub struct Connection {
+    state: Arc<ServerState>,
     rx: ServerRX,
     tx: ServerTX,
     data: ConnectionData,
 }
 
 impl Connection {
-    async fn work(mut self) {
-        loop {
-            let Ok(Ok(Some(ClientMessage {
-                data: Some(client_message::Data::Hearthbeat(client_msg)),
-            }))) = tokio::time::timeout(v1::PING_PERIOD + v1::PING_GRACE, self.rx.message()).await
-            else {
-                return;
-            };
-
-            let err = match check_permission(&self.data.license) {
-                Ok(_) => None,
-                Err(e) => Some(e.into()),
-            };
+    /// Reloads the license so admin changes reach live sessions.
+    /// On database errors the last known state is kept.
+    async fn refresh_license(&mut self) -> Result<(), LicenseError> {
+        match self.state.license(self.data.license.id).await {
+            Ok(Some(license)) => self.data.license = license,
+            Ok(None) => return Err(LicenseError::InvalidKey),
+            Err(err) => tracing::error!("license.refresh_failed: {err}"),
+        }
+        check_permission(&self.data.license)
+    }
 
-            let hearthbeat_data = ServerHearthbeatData { error: err };
+    async fn log(&mut self, kind: EventKind, data: serde_json::Value) {
+        self.state
+            .log_event(self.data.license.id, kind, self.data.peer.event(data))
+            .await;
+    }
 
-            let signature = v1::SignatureSchema::sign(
-                &hearthbeat_data,
-                client_msg.nonce,
+    async fn send_hearthbeat(&mut self, nonce: u64, error: Option<LicenseError>) {
+        // a live session always sees the current state of its license
+        let license = error.is_none().then(|| {
+            let mut license = super::license_response(&self.data.license);
+            // keeps the token valid for as long as the seat is held
+            license.resumption_token = sign_resumption_token(
+                self.data.seat.id,
+                &self.data.license,
+                nonce,
                 &mut self.data.signing_key,
             );
+            license
+        });
+        let hearthbeat_data = ServerHearthbeatData {
+            error: error.map(Into::into),
+            license,
+        };
 
-            let response = ServerMessage {
-                data: Some(server_message::Data::Heathbeat(ServerHearthbeat {
-                    nonce: client_msg.nonce,
-                    signature,
-                    data: Some(hearthbeat_data),
-                })),
+        let signature =
+            v1::SignatureSchema::sign(&hearthbeat_data, nonce, &mut self.data.signing_key);
+
+        let response = ServerMessage {
+            data: Some(server_message::Data::Heathbeat(ServerHearthbeat {
+                nonce,
+                signature,
+                data: Some(hearthbeat_data),
+            })),
+        };
+
+        let _ = self.tx.send(Ok(response)).await;
+    }
+
+    /// Releases the seat before confirming, so the client can reconnect right after
+    async fn send_goodbye(&mut self, nonce: u64) {
+        let released = match self.data.seat.release().await {
+            Ok(released) => released,
+            Err(err) => {
+                // dropping the seat tries again
+                tracing::error!("session.release_failed: {err}");
+                false
+            }
+        };
+        let goodbye_data = ServerGoodbyeData { released };
+        let signature = v1::SignatureSchema::sign(&goodbye_data, nonce, &mut self.data.signing_key);
+
+        let response = ServerMessage {
+            data: Some(server_message::Data::Goodbye(ServerGoodbye {
+                nonce,
+                signature,
+                data: Some(goodbye_data),
+            })),
+        };
+        let _ = self.tx.send(Ok(response)).await;
+    }
+
+    /// Serves heartbeats until the session ends, returning why it ended
+    async fn work(&mut self) -> &'static str {
+        // a termination has no ping to answer, so its denial waits for the client's next one
+        let mut terminated = false;
+        loop {
+            let message = tokio::select! {
+                _ = self.data.seat.terminated(), if !terminated => {
+                    terminated = true;
+                    continue;
+                }
+                message = tokio::time::timeout(v1::PING_PERIOD + v1::PING_GRACE, self.rx.message()) => message,
+            };
+            let message = match message {
+                Err(_) => return "timeout",
+                Ok(Err(_)) => return "stream_error",
+                Ok(Ok(None)) => return "client_closed",
+                Ok(Ok(Some(message))) => message,
+            };
+            let client_msg = match message.data {
+                Some(client_message::Data::Hearthbeat(client_msg)) => client_msg,
+                Some(client_message::Data::Goodbye(goodbye)) => {
+                    self.send_goodbye(goodbye.nonce).await;
+                    return "client_goodbye";
+                }
+                _ => return "unexpected_message",
+            };
+
+            if !terminated {
+                match self.data.seat.touch().await {
+                    Ok(alive) => terminated = !alive,
+                    Err(err) => tracing::error!("session.touch_failed: {err}"),
+                }
+            }
+            // also covers terminations through another server instance
+            if terminated {
+                self.send_hearthbeat(client_msg.nonce, Some(LicenseError::SessionTerminated))
+                    .await;
+                return "terminated";
+            }
+
+            let err = match self.refresh_license().await {
+                Ok(_) => None,
+                Err(e) => {
+                    self.log(
+                        EventKind::HeartbeatDenied,
+                        json!({ "error": e.as_str_name() }),
+                    )
+                    .await;
+                    Some(e)
+                }
             };
 
-            let _ = self.tx.send(Ok(response)).await;
+            self.send_hearthbeat(client_msg.nonce, err).await;
         }
     }
 }
@@ -91,28 +207,65 @@ async fn try_get_request(rx: &mut ServerRX) -> Result<(info_request::Request, u6
 }
 
 pub fn check_permission(license: &license::Model) -> Result<(), LicenseError> {
+    if license.revoked_at.is_some() {
+        return Err(LicenseError::Revoked);
+    }
     if Utc::now() > license.expiry {
         return Err(LicenseError::Expired);
     }
 
     Ok(())
 }
-pub async fn check_permission_connect(
+async fn check_permission_connect(
     license: &license::Model,
-    state: &ServerState,
-) -> Result<(), LicenseError> {
+    state: &Arc<ServerState>,
+    peer: &PeerInfo,
+    machine_id: &str,
+    resumes: Option<Uuid>,
+) -> Result<Seat, LicenseError> {
     check_permission(license)?;
-    if let Some(connections_limit) = license.policy_limit_connections {
-        let mut connections_lock = state.connections.lock().await;
+    state
+        .reserve_seat(license, peer.addr, machine_id, resumes)
+        .await
+}
 
-        let key_entry = connections_lock.entry(license.id.clone()).or_insert(0);
+/// Encoded token letting a reconnecting client take over `session`, valid until the
+/// session would be reaped as stale
+fn sign_resumption_token(
+    session: Uuid,
+    license: &license::Model,
+    nonce: u64,
+    key: &mut SigningKey,
+) -> Vec<u8> {
+    let now = Utc::now();
+    let payload = resumption_token::Payload {
+        session: session.to_string(),
+        key_id: license.id.to_string(),
+        issued_at: Some(now.to_protobuf()),
+        expires_at: Some((now + SESSION_TIMEOUT).to_protobuf()),
+    };
+    ResumptionToken::sign(payload, nonce, key).encode_to_vec()
+}
 
-        // this function is called before incrementing connection counter
-        if (*key_entry) + 1 > connections_limit {
-            return Err(LicenseError::TooManySessions);
-        }
+/// Session named by a client's resumption token, if we signed it for this license and
+/// it hasn't expired. Anything else gets a fresh seat.
+fn resumed_session(token: &[u8], license: &license::Model, key: &SigningKey) -> Option<Uuid> {
+    if token.is_empty() {
+        return None;
     }
-    Ok(())
+    let token = ResumptionToken::decode(token).ok()?;
+    let payload = token.verify(&VerifyingKey(key.verifying_key()))?;
+    if payload.key_id != license.id.to_string() {
+        return None;
+    }
+
+    let issued_at = DateTime::try_from_protobuf(payload.issued_at.as_ref()?)?;
+    let expires_at = DateTime::try_from_protobuf(payload.expires_at.as_ref()?)?;
+    let now = Utc::now();
+    if now < issued_at || now >= expires_at {
+        return None;
+    }
+    payload.session.parse().ok()
 }
 
 async fn try_get_license(
@@ -120,14 +273,13 @@ async fn try_get_license(
     request: info_request::Request,
 ) -> Result<license::Model, LicenseError> {
     println!("'{}'", request.key_id);
-    let license =
-        entities::license::Entity::find_by_id(request.key_id.parse::<Uuid>().map_err(|_| {
+    let license = state
+        .license(request.key_id.parse::<Uuid>().map_err(|_| {
             // tracing::info!("key.invalid");
             println!("key.invalid");
 
             LicenseError::InvalidKey
         })?)
-        .one(&state.db)
         .await
         .map_err(|_| LicenseError::Internal)?;
 
@@ -143,41 +295,45 @@ async fn try_get_license(
         return Err(LicenseError::InvalidKey);
     };
 
-    check_permission_connect(&license, state).await?;
-
     Ok(license)
 }
 
-pub async fn handle(state: Arc<ServerState>, tx: ServerTX, mut rx: ServerRX) {
-    tracing::info!("server.conn");
+/// Signing key of the app with the given verifying key, if there is one
+async fn app_signing_key(state: &ServerState, public_key: &[u8]) -> Option<SigningKey> {
+    if public_key.is_empty() {
+        return None;
+    }
+    let app = entities::app::Entity::find()
+        .filter(entities::app::Column::PublicKey.eq(public_key))
+        .one(&state.db)
+        .await
+        .ok()??;
+    SigningKey::try_from(app.private_key.as_slice()).ok()
+}
 
-    // let _app = app::ActiveModel {
-    //     name: Set("netsharesoft".to_owned()),
-    //     private_key: Set(hex::decode(
-    //         "a03a0327de44f0d47f2e062cd504191532d7b07ba51aae78730bc66cac8c54b3",
-    //     )
-    //     .unwrap()),
-    //     public_key: Set(hex::decode(
-    //         "34fd6ff70f4f452c191cfc23dbf9f9e6dcdb23d0be01fee0f724a2182f76aee0",
-    //     )
-    //     .unwrap()),
-    //     data_schema: Set(serde_json::json!({})),
-    // }
-    // .insert(&state.db)
-    // .await
-    // .unwrap();
-
-    // let _license = license::ActiveModel {
-    //     id: Set(Uuid::from_str("bf024a65-2a58-45d9-b480-5a1795becd90").unwrap()),
-    //     holder: Set("me".to_string()),
-    //     expiry: Set(Utc::now() + chrono::Duration::days(10)),
-    //     extra_data: Set(json!({})),
-    //     policy_limit_connections: Set(Some(1)),
-    //     app: Set("netsharesoft".to_owned()),
-    // }
-    // .insert(&state.db)
-    // .await
-    // .unwrap();
+/// Unsigned without a key, clients only act on rejections they can verify
+async fn send_license_error(
+    tx: &ServerTX,
+    nonce: u64,
+    err: LicenseError,
+    key: Option<&mut SigningKey>,
+) {
+    let _ = tx
+        .send(Ok(ServerMessage {
+            data: Some(server_message::Data::Auth(InfoResponse::error(
+                err, nonce, key,
+            ))),
+        }))
+        .await;
+}
+
+pub async fn handle(
+    state: Arc<ServerState>,
+    tx: ServerTX,
+    mut rx: ServerRX,
+    addr: Option<SocketAddr>,
+) {
+    tracing::info!("server.conn");
 
     let (request, nonce) = match try_get_request(&mut rx).await {
         Ok(inner) => inner,
@@ -188,19 +344,18 @@ pub async fn handle(state: Arc<ServerState>, tx: ServerTX, mut rx: ServerRX) {
         } // if connection closed meanwhile, we don't care
     };
 
+    let peer = PeerInfo { addr, nonce };
+    let machine_id = request.machine_id.clone();
+    let resumption_token = request.resumption_token.clone();
+    let requested_app = request.app.clone();
+
     let license = match try_get_license(state.as_ref(), request).await {
         Ok(license) => license,
         Err(err) => {
-            let _ = tx
-                .send(Ok(ServerMessage {
-                    data: Some(server_message::Data::Auth(InfoResponse {
-                        nonce,
-                        signature: Vec::new(),
-                        result: Some(info_response::Result::Error(err.into())),
-                    })),
-                }))
-                .await;
-
+            // unknown keys have no license to attach the event to
+            tracing::info!("handshake.denied: {}", err.as_str_name());
+            let mut key = app_signing_key(&state, &requested_app).await;
+            send_license_error(&tx, nonce, err, key.as_mut()).await;
             return;
         }
     };
@@ -222,11 +377,42 @@ pub async fn handle(state: Arc<ServerState>, tx: ServerTX, mut rx: ServerRX) {
         return;
     };
 
-    let response = info_response::Response {
-        expiry: Some(license.expiry.to_protobuf()),
-        extra_data: license.extra_data.to_string(),
+    // a key of another app is as unknown to the requested one
+    if !requested_app.is_empty() && requested_app != app.public_key {
+        tracing::info!(
+            "handshake.denied: {}",
+            LicenseError::InvalidKey.as_str_name()
+        );
+        let mut key = app_signing_key(&state, &requested_app).await;
+        send_license_error(&tx, nonce, LicenseError::InvalidKey, key.as_mut()).await;
+        return;
+    }
+
+    let resumes = resumed_session(&resumption_token, &license, &key);
+    // released when the connection ends, whatever way it does
+    let seat = match check_permission_connect(&license, &state, &peer, &machine_id, resumes).await {
+        Ok(seat) => seat,
+        Err(err) => {
+            let kind = match err {
+                LicenseError::TooManySessions => EventKind::SessionLimit,
+                LicenseError::MachineLimit => EventKind::MachineLimit,
+                _ => EventKind::HandshakeDenied,
+            };
+            state
+                .log_event(
+                    license.id,
+                    kind,
+                    peer.event(json!({ "error": err.as_str_name(), "machine": machine_id })),
+                )
+                .await;
+            send_license_error(&tx, nonce, err, Some(&mut key)).await;
+            return;
+        }
     };
 
+    let mut response = super::license_response(&license);
+    response.resumption_token = sign_resumption_token(seat.id, &license, nonce, &mut key);
+
     let signature = v1::SignatureSchema::sign(&response, nonce, &mut key);
 
     let response = ServerMessage {
@@ -241,20 +427,140 @@ pub async fn handle(state: Arc<ServerState>, tx: ServerTX, mut rx: ServerRX) {
         return;
     }
 
-    // mark new connection
-    let license_id = license.id.clone();
-    state.inc_conn(license_id.clone()).await;
-
-    let connection = Connection {
+    let mut connection = Connection {
+        state: state.clone(),
         rx,
         tx,
         data: ConnectionData {
             signing_key: key,
             license,
+            peer,
+            seat,
         },
     };
 
-    connection.work().await;
+    let session = connection.data.seat.id;
+    let resumed = connection.data.seat.resumed;
+    connection
+        .log(
+            EventKind::HandshakeAccepted,
+            json!({ "session": session, "resumed": resumed }),
+        )
+        .await;
+    let reason = connection.work().await;
+    connection
+        .log(
+            EventKind::SessionClosed,
+            json!({ "session": session, "reason": reason }),
+        )
+        .await;
+}
 
-    state.dec_conn(license_id).await;
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use proto::software::v1::SignatureSchema;
+    use sea_orm::prelude::Json;
+
+    use super::*;
+
+    fn license() -> license::Model {
+        license::Model {
+            id: Uuid::new_v4(),
+            holder: "holder".to_owned(),
+            expiry: Utc::now() + TimeDelta::days(1),
+            extra_data: Json::Null,
+            policy_limit_connections: None,
+            app: "app".to_owned(),
+            revoked_at: None,
+            revoke_reason: None,
+            policy_limit_machines: None,
+        }
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::try_from(&rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    fn token_valid_between(
+        session: Uuid,
+        license: &license::Model,
+        issued_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        key: &mut SigningKey,
+    ) -> Vec<u8> {
+        let payload = resumption_token::Payload {
+            session: session.to_string(),
+            key_id: license.id.to_string(),
+            issued_at: Some(issued_at.to_protobuf()),
+            expires_at: Some(expires_at.to_protobuf()),
+        };
+        ResumptionToken::sign(payload, 1, key).encode_to_vec()
+    }
+
+    #[test]
+    fn fresh_token_resumes() {
+        let (license, mut key, session) = (license(), key(), Uuid::new_v4());
+        let token = sign_resumption_token(session, &license, 1, &mut key);
+        assert_eq!(resumed_session(&token, &license, &key), Some(session));
+        assert_eq!(resumed_session(&token, &self::license(), &key), None);
+        assert_eq!(resumed_session(&token, &license, &self::key()), None);
+    }
+
+    #[test]
+    fn expired_token_is_ignored() {
+        let (license, mut key, session) = (license(), key(), Uuid::new_v4());
+        let now = Utc::now();
+
+        let expired = token_valid_between(
+            session,
+            &license,
+            now - TimeDelta::minutes(2),
+            now - TimeDelta::minutes(1),
+            &mut key,
+        );
+        assert_eq!(resumed_session(&expired, &license, &key), None);
+
+        let early = token_valid_between(
+            session,
+            &license,
+            now + TimeDelta::minutes(1),
+            now + TimeDelta::minutes(2),
+            &mut key,
+        );
+        assert_eq!(resumed_session(&early, &license, &key), None);
+    }
+
+    #[test]
+    fn token_signature_is_domain_separated() {
+        let (license, mut key, session) = (license(), key(), Uuid::new_v4());
+        let payload = resumption_token::Payload {
+            session: session.to_string(),
+            key_id: license.id.to_string(),
+            issued_at: Some(Utc::now().to_protobuf()),
+            expires_at: Some((Utc::now() + SESSION_TIMEOUT).to_protobuf()),
+        };
+        let verifying_key = VerifyingKey(key.verifying_key());
+
+        // a plain signature over the same bytes doesn't make a token
+        let forged = ResumptionToken {
+            signature: SignatureSchema::sign(&payload, 1, &mut key),
+            payload: Some(payload.clone()),
+            nonce: 1,
+        };
+        assert_eq!(
+            resumed_session(&forged.encode_to_vec(), &license, &key),
+            None
+        );
+
+        // nor does a token signature pass for a plain one
+        let token = ResumptionToken::sign(payload.clone(), 1, &mut key);
+        assert!(token.verify(&verifying_key).is_some());
+        assert!(!SignatureSchema::verify(
+            &payload,
+            1,
+            &verifying_key,
+            &token.signature
+        ));
+    }
 }
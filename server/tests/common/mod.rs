@@ -0,0 +1,252 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use proto::admin_client::v1::{
+    license_server_client::LicenseServerClient, CreateAppReq, CreateLicenseReq, Policy,
+    ADMIN_KEY_METADATA,
+};
+use proto::software::v1::{
+    authority_client::AuthorityClient, client_message, info_request, server_message, ClientGoodbye,
+    ClientHearthbeat, ClientMessage, InfoRequest, InfoResponse, ServerGoodbye, ServerHearthbeat,
+    ServerMessage, VerifyingKey,
+};
+use proto::ChronoExt;
+use server::{admin_v1_server::AdminV1, v1_server::SoftwareV1, ServerState};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{transport::Channel, Streaming};
+use uuid::Uuid;
+
+pub const ROOT_KEY: Uuid = Uuid::from_u128(0x5eed);
+
+/// Wraps a message into a request authenticated with `key`
+pub fn authed<T>(key: Uuid, message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request
+        .metadata_mut()
+        .insert(ADMIN_KEY_METADATA, key.to_string().parse().unwrap());
+    request
+}
+
+pub struct TestServer {
+    pub state: Arc<ServerState>,
+    pub channel: Channel,
+}
+
+impl TestServer {
+    pub async fn start() -> eyre::Result<Self> {
+        Self::start_with_database("sqlite::memory:").await
+    }
+
+    pub async fn start_with_database(database_uri: &str) -> eyre::Result<Self> {
+        let config = server::Config {
+            database_uri: database_uri.to_owned(),
+            root_admin_key: Some(ROOT_KEY),
+        };
+        let state = Arc::new(ServerState::new(config).await?);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let router = tonic::transport::Server::builder()
+            .add_service(SoftwareV1::new(state.clone()))
+            .add_service(AdminV1::new(state.clone()));
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))?
+            .connect()
+            .await?;
+
+        Ok(Self { state, channel })
+    }
+
+    pub fn admin(&self) -> LicenseServerClient<Channel> {
+        LicenseServerClient::new(self.channel.clone())
+    }
+
+    pub fn software(&self) -> AuthorityClient<Channel> {
+        AuthorityClient::new(self.channel.clone())
+    }
+}
+
+impl TestServer {
+    pub async fn create_app(&self, name: &str) -> eyre::Result<VerifyingKey> {
+        let app = self
+            .admin()
+            .create_app(authed(
+                ROOT_KEY,
+                CreateAppReq {
+                    name: name.to_owned(),
+                    app_owner: "owner".to_owned(),
+                    ..Default::default()
+                },
+            ))
+            .await?
+            .into_inner();
+        app.public_key
+            .parse()
+            .map_err(|_| eyre::eyre!("invalid public key"))
+    }
+
+    pub async fn create_license(
+        &self,
+        app: &str,
+        expiry: DateTime<Utc>,
+        limit_connections: Option<u64>,
+    ) -> eyre::Result<String> {
+        let policy = Policy {
+            limit_connections,
+            ..Default::default()
+        };
+        self.create_license_with_policy(app, expiry, policy).await
+    }
+
+    pub async fn create_license_with_policy(
+        &self,
+        app: &str,
+        expiry: DateTime<Utc>,
+        policy: Policy,
+    ) -> eyre::Result<String> {
+        let license = self
+            .admin()
+            .create_license(authed(
+                ROOT_KEY,
+                CreateLicenseReq {
+                    holder: "holder".to_owned(),
+                    expiry: Some(expiry.to_protobuf()),
+                    extra_data: "{}".to_owned(),
+                    policy: Some(policy),
+                    app: app.to_owned(),
+                },
+            ))
+            .await?
+            .into_inner();
+        Ok(license.license_key)
+    }
+}
+
+/// Raw client side of a `Hearthbeat` stream
+pub struct Session {
+    tx: mpsc::Sender<ClientMessage>,
+    rx: Streaming<ServerMessage>,
+}
+
+impl Session {
+    pub async fn open(server: &TestServer, key: &str) -> eyre::Result<(Self, InfoResponse)> {
+        Self::open_on(server, key, "").await
+    }
+
+    /// Opens a session from the machine with the given fingerprint
+    pub async fn open_on(
+        server: &TestServer,
+        key: &str,
+        machine: &str,
+    ) -> eyre::Result<(Self, InfoResponse)> {
+        Self::open_with(
+            server,
+            info_request::Request {
+                key_id: key.to_owned(),
+                machine_id: machine.to_owned(),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Opens a session naming the app it is checked for, as clients do
+    pub async fn open_for(
+        server: &TestServer,
+        key: &str,
+        app: &VerifyingKey,
+    ) -> eyre::Result<(Self, InfoResponse)> {
+        Self::open_with(
+            server,
+            info_request::Request {
+                key_id: key.to_owned(),
+                app: app.0.to_bytes().to_vec(),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Opens a session that takes over the one `token` was issued to
+    pub async fn resume(
+        server: &TestServer,
+        key: &str,
+        token: &[u8],
+    ) -> eyre::Result<(Self, InfoResponse)> {
+        Self::open_with(
+            server,
+            info_request::Request {
+                key_id: key.to_owned(),
+                resumption_token: token.to_vec(),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn open_with(
+        server: &TestServer,
+        request: info_request::Request,
+    ) -> eyre::Result<(Self, InfoResponse)> {
+        let (tx, client_rx) = mpsc::channel(1);
+        let mut rx = server
+            .software()
+            .hearthbeat(ReceiverStream::new(client_rx))
+            .await?
+            .into_inner();
+
+        tx.send(ClientMessage {
+            data: Some(client_message::Data::Auth(InfoRequest {
+                req: Some(request),
+                nonce: 1,
+            })),
+        })
+        .await?;
+
+        let Some(ServerMessage {
+            data: Some(server_message::Data::Auth(response)),
+        }) = rx.message().await?
+        else {
+            eyre::bail!("expected auth response");
+        };
+
+        Ok((Self { tx, rx }, response))
+    }
+
+    pub async fn heartbeat(&mut self, nonce: u64) -> eyre::Result<ServerHearthbeat> {
+        self.tx
+            .send(ClientMessage {
+                data: Some(client_message::Data::Hearthbeat(ClientHearthbeat { nonce })),
+            })
+            .await?;
+
+        let Some(ServerMessage {
+            data: Some(server_message::Data::Heathbeat(heartbeat)),
+        }) = self.rx.message().await?
+        else {
+            eyre::bail!("expected heartbeat response");
+        };
+        Ok(heartbeat)
+    }
+
+    pub async fn goodbye(&mut self, nonce: u64) -> eyre::Result<ServerGoodbye> {
+        self.tx
+            .send(ClientMessage {
+                data: Some(client_message::Data::Goodbye(ClientGoodbye { nonce })),
+            })
+            .await?;
+
+        let Some(ServerMessage {
+            data: Some(server_message::Data::Goodbye(goodbye)),
+        }) = self.rx.message().await?
+        else {
+            eyre::bail!("expected goodbye response");
+        };
+        Ok(goodbye)
+    }
+}
@@ -0,0 +1,124 @@
+use chrono::{Duration, Utc};
+use common::{authed, ROOT_KEY};
+use proto::{
+    admin_client::v1::{CreateAppReq, CreateLicenseReq, Policy},
+    software::v1::VerifyingKey,
+    ChronoExt,
+};
+use uuid::Uuid;
+
+mod common;
+
+fn license_req(app: &str) -> CreateLicenseReq {
+    CreateLicenseReq {
+        holder: "holder".to_owned(),
+        expiry: Some((Utc::now() + Duration::days(1)).to_protobuf()),
+        extra_data: r#"{"tier": "pro"}"#.to_owned(),
+        policy: Some(Policy {
+            limit_connections: Some(1),
+            limit_machines: None,
+        }),
+        app: app.to_owned(),
+    }
+}
+
+#[tokio::test]
+async fn test_create_app_and_license() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let mut admin = server.admin();
+
+    let app = admin
+        .create_app(authed(
+            ROOT_KEY,
+            CreateAppReq {
+                name: "app".to_owned(),
+                app_owner: "owner".to_owned(),
+                data_schema: "{}".to_owned(),
+                admin_key: String::new(),
+            },
+        ))
+        .await?
+        .into_inner();
+    assert_eq!(app.id, "app");
+    assert!(app.public_key.parse::<VerifyingKey>().is_ok());
+
+    let duplicate = admin
+        .create_app(authed(
+            ROOT_KEY,
+            CreateAppReq {
+                name: "app".to_owned(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(duplicate.code(), tonic::Code::AlreadyExists);
+
+    let license = admin
+        .create_license(authed(ROOT_KEY, license_req("app")))
+        .await?
+        .into_inner();
+    assert!(license.license_key.parse::<Uuid>().is_ok());
+
+    let missing_app = admin
+        .create_license(authed(ROOT_KEY, license_req("missing")))
+        .await
+        .unwrap_err();
+    assert_eq!(missing_app.code(), tonic::Code::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_key_scoping() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let mut admin = server.admin();
+
+    let key_a = Uuid::new_v4();
+    for (name, key) in [("a", key_a), ("b", Uuid::new_v4())] {
+        admin
+            .create_app(authed(
+                ROOT_KEY,
+                CreateAppReq {
+                    name: name.to_owned(),
+                    app_owner: "owner".to_owned(),
+                    admin_key: key.to_string(),
+                    ..Default::default()
+                },
+            ))
+            .await?;
+    }
+
+    let missing = admin.create_license(license_req("a")).await.unwrap_err();
+    assert_eq!(missing.code(), tonic::Code::Unauthenticated);
+
+    let unknown = admin
+        .create_license(authed(Uuid::new_v4(), license_req("a")))
+        .await
+        .unwrap_err();
+    assert_eq!(unknown.code(), tonic::Code::Unauthenticated);
+
+    admin
+        .create_license(authed(key_a, license_req("a")))
+        .await?;
+
+    let foreign = admin
+        .create_license(authed(key_a, license_req("b")))
+        .await
+        .unwrap_err();
+    assert_eq!(foreign.code(), tonic::Code::PermissionDenied);
+
+    let not_root = admin
+        .create_app(authed(
+            key_a,
+            CreateAppReq {
+                name: "c".to_owned(),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(not_root.code(), tonic::Code::PermissionDenied);
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use chrono::{Duration, Utc};
+use common::{authed, Session, ROOT_KEY};
+use proto::{
+    admin_client::v1::{CreateAppReq, ExtendLicense, ListLicenseEventsReq},
+    ChronoExt,
+};
+use uuid::Uuid;
+
+mod common;
+
+#[tokio::test]
+async fn test_list_license_events() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    let (mut session, _) = Session::open(&server, &key).await?;
+    session.heartbeat(2).await?;
+
+    server
+        .admin()
+        .extend_license(authed(
+            ROOT_KEY,
+            ExtendLicense {
+                license: key.clone(),
+                to_date: Some((Utc::now() + Duration::days(2)).to_protobuf()),
+            },
+        ))
+        .await?;
+
+    // walk the log one event per page
+    let mut kinds = Vec::new();
+    let mut cursor = String::new();
+    loop {
+        let page = server
+            .admin()
+            .list_license_events(authed(
+                ROOT_KEY,
+                ListLicenseEventsReq {
+                    license: Some(key.clone()),
+                    cursor,
+                    page_size: 1,
+                    ..Default::default()
+                },
+            ))
+            .await?
+            .into_inner();
+        assert!(page.events.len() <= 1);
+        kinds.extend(page.events.into_iter().map(|event| event.kind));
+        if page.next_cursor.is_empty() {
+            break;
+        }
+        cursor = page.next_cursor;
+    }
+    assert_eq!(kinds, ["handshake.accepted", "license.extended"]);
+
+    let extended = server
+        .admin()
+        .list_license_events(authed(
+            ROOT_KEY,
+            ListLicenseEventsReq {
+                app: Some("app".to_owned()),
+                kind: Some("license.extended".to_owned()),
+                since: Some((Utc::now() - Duration::hours(1)).to_protobuf()),
+                ..Default::default()
+            },
+        ))
+        .await?
+        .into_inner();
+    assert_eq!(extended.events.len(), 1);
+    assert_eq!(extended.events[0].license, key);
+
+    let other_key = Uuid::new_v4();
+    server
+        .admin()
+        .create_app(authed(
+            ROOT_KEY,
+            CreateAppReq {
+                name: "other".to_owned(),
+                admin_key: other_key.to_string(),
+                ..Default::default()
+            },
+        ))
+        .await?;
+    let foreign = server
+        .admin()
+        .list_license_events(authed(
+            other_key,
+            ListLicenseEventsReq {
+                license: Some(key.clone()),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(foreign.code(), tonic::Code::PermissionDenied);
+
+    let own = server
+        .admin()
+        .list_license_events(authed(other_key, ListLicenseEventsReq::default()))
+        .await?
+        .into_inner();
+    assert!(own.events.is_empty());
+
+    Ok(())
+}
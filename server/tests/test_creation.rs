@@ -2,6 +2,7 @@
 async fn test_creation() -> eyre::Result<()> {
     let config = server::Config {
         database_uri: "sqlite://db.data?mode=rwc".to_owned(),
+        root_admin_key: None,
     };
     let server = server::ServerState::new(config).await?;
     drop(server);
@@ -0,0 +1,133 @@
+use chrono::{Duration, Utc};
+use common::{authed, Session, ROOT_KEY};
+use proto::{
+    admin_client::v1::{ExtendLicense, RevokeLicenseReq},
+    software::v1::{info_response, LicenseError, SignatureSchema},
+    ChronoExt,
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_extend_license_reaches_live_session() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let public_key = server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    let (mut session, response) = Session::open(&server, &key).await?;
+    assert!(matches!(
+        response.result,
+        Some(info_response::Result::Ok(_))
+    ));
+
+    let heartbeat = session.heartbeat(2).await?;
+    assert_eq!(heartbeat.data.unwrap().error, None);
+
+    server
+        .admin()
+        .extend_license(authed(
+            ROOT_KEY,
+            ExtendLicense {
+                license: key.clone(),
+                to_date: Some((Utc::now() - Duration::days(1)).to_protobuf()),
+            },
+        ))
+        .await?;
+
+    let heartbeat = session.heartbeat(3).await?;
+    let data = heartbeat.data.unwrap();
+    assert!(SignatureSchema::verify(
+        &data,
+        heartbeat.nonce,
+        &public_key,
+        &heartbeat.signature
+    ));
+    assert_eq!(data.error, Some(LicenseError::Expired.into()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_revoke_license() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    let (mut session, _) = Session::open(&server, &key).await?;
+
+    server
+        .admin()
+        .revoke_license(authed(
+            ROOT_KEY,
+            RevokeLicenseReq {
+                license: key.clone(),
+                reason: "chargeback".to_owned(),
+            },
+        ))
+        .await?;
+
+    let heartbeat = session.heartbeat(2).await?;
+    assert_eq!(
+        heartbeat.data.unwrap().error,
+        Some(LicenseError::Revoked.into())
+    );
+
+    let (_, response) = Session::open(&server, &key).await?;
+    assert_eq!(
+        response.result,
+        Some(info_response::Result::Error(LicenseError::Revoked.into()))
+    );
+
+    let again = server
+        .admin()
+        .revoke_license(authed(
+            ROOT_KEY,
+            RevokeLicenseReq {
+                license: key,
+                reason: String::new(),
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(again.code(), tonic::Code::FailedPrecondition);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat_carries_current_license() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    let (mut session, response) = Session::open(&server, &key).await?;
+    let Some(info_response::Result::Ok(info)) = response.result else {
+        eyre::bail!("expected license details");
+    };
+    assert_eq!(info.holder, "holder");
+
+    let extended = Utc::now() + Duration::days(30);
+    server
+        .admin()
+        .extend_license(authed(
+            ROOT_KEY,
+            ExtendLicense {
+                license: key.clone(),
+                to_date: Some(extended.to_protobuf()),
+            },
+        ))
+        .await?;
+
+    let heartbeat = session.heartbeat(2).await?;
+    let license = heartbeat.data.unwrap().license.unwrap();
+    assert_eq!(license.expiry, Some(extended.to_protobuf()));
+    assert_eq!(license.holder, "holder");
+
+    Ok(())
+}
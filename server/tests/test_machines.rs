@@ -0,0 +1,216 @@
+use chrono::{Duration, Utc};
+use common::{authed, Session, ROOT_KEY};
+use proto::{
+    admin_client::v1::{DeactivateMachineReq, LabelMachineReq, ListMachinesReq, Policy},
+    software::v1::{info_response, LicenseError, ReleaseMachineRequest},
+};
+
+mod common;
+
+fn error(response: &proto::software::v1::InfoResponse) -> Option<LicenseError> {
+    match response.result {
+        Some(info_response::Result::Error(err)) => LicenseError::try_from(err).ok(),
+        _ => None,
+    }
+}
+
+/// The session's next ping is answered with a termination
+async fn assert_terminated(session: &mut Session, nonce: u64) -> eyre::Result<()> {
+    let denial = session.heartbeat(nonce).await?;
+    assert_eq!(denial.nonce, nonce);
+    assert_eq!(
+        denial.data.unwrap().error,
+        Some(LicenseError::SessionTerminated.into())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_machine_limit() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(1),
+            Policy {
+                limit_machines: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let (_first, response) = Session::open_on(&server, &key, "machine-a").await?;
+    assert_eq!(error(&response), None);
+
+    let (_, response) = Session::open_on(&server, &key, "machine-b").await?;
+    assert_eq!(error(&response), Some(LicenseError::MachineLimit));
+
+    // clients that can't identify their machine don't get around the limit
+    let (_, response) = Session::open(&server, &key).await?;
+    assert_eq!(error(&response), Some(LicenseError::MachineUnknown));
+
+    // an activated machine can keep reconnecting
+    let (_second, response) = Session::open_on(&server, &key, "machine-a").await?;
+    assert_eq!(error(&response), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refused_seat_keeps_machine_slot() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(1),
+            Policy {
+                limit_connections: Some(1),
+                limit_machines: Some(2),
+            },
+        )
+        .await?;
+
+    let (mut first, response) = Session::open_on(&server, &key, "machine-a").await?;
+    assert_eq!(error(&response), None);
+
+    let (_, response) = Session::open_on(&server, &key, "machine-b").await?;
+    assert_eq!(error(&response), Some(LicenseError::TooManySessions));
+
+    // machine-b was never activated, so once the seat is free another machine still fits
+    first.goodbye(2).await?;
+    let (_, response) = Session::open_on(&server, &key, "machine-c").await?;
+    assert_eq!(error(&response), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unlimited_machines() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    for machine in ["machine-a", "machine-b", ""] {
+        let (_, response) = Session::open_on(&server, &key, machine).await?;
+        assert_eq!(error(&response), None);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manage_machines() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(1),
+            Policy {
+                limit_machines: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let (mut session, response) = Session::open_on(&server, &key, "machine-a").await?;
+    assert_eq!(error(&response), None);
+
+    let machines = server
+        .admin()
+        .list_machines(authed(
+            ROOT_KEY,
+            ListMachinesReq {
+                license: key.clone(),
+            },
+        ))
+        .await?
+        .into_inner()
+        .machines;
+    assert_eq!(machines.len(), 1);
+    assert_eq!(machines[0].fingerprint, "machine-a");
+    assert_eq!(machines[0].label, None);
+    let machine = machines[0].id;
+
+    server
+        .admin()
+        .label_machine(authed(
+            ROOT_KEY,
+            LabelMachineReq {
+                machine,
+                label: Some("office laptop".to_owned()),
+            },
+        ))
+        .await?;
+    let machines = server
+        .admin()
+        .list_machines(authed(
+            ROOT_KEY,
+            ListMachinesReq {
+                license: key.clone(),
+            },
+        ))
+        .await?
+        .into_inner()
+        .machines;
+    assert_eq!(machines[0].label.as_deref(), Some("office laptop"));
+
+    // deactivating frees the slot for another machine
+    server
+        .admin()
+        .deactivate_machine(authed(ROOT_KEY, DeactivateMachineReq { machine }))
+        .await?;
+    assert_terminated(&mut session, 2).await?;
+    let (_, response) = Session::open_on(&server, &key, "machine-b").await?;
+    assert_eq!(error(&response), None);
+
+    let status = server
+        .admin()
+        .deactivate_machine(authed(ROOT_KEY, DeactivateMachineReq { machine }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_release_machine() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(1),
+            Policy {
+                limit_machines: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let (mut session, response) = Session::open_on(&server, &key, "machine-a").await?;
+    assert_eq!(error(&response), None);
+
+    let release = |machine: &str| {
+        let mut software = server.software();
+        let request = ReleaseMachineRequest {
+            key_id: key.clone(),
+            machine_id: machine.to_owned(),
+        };
+        async move { software.release_machine(request).await }
+    };
+
+    assert!(!release("machine-b").await?.into_inner().released);
+    assert!(release("machine-a").await?.into_inner().released);
+    assert_terminated(&mut session, 2).await?;
+
+    let (_, response) = Session::open_on(&server, &key, "machine-b").await?;
+    assert_eq!(error(&response), None);
+
+    Ok(())
+}
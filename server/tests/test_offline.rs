@@ -0,0 +1,228 @@
+use chrono::{Duration, Utc};
+use common::{authed, Session, ROOT_KEY};
+use prost::Message;
+use proto::{
+    admin_client::v1::{ActivateOfflineReq, IssueOfflineLicenseReq, Policy, RevokeLicenseReq},
+    software::v1::{
+        info_response, ActivationRequest, LicenseError, OfflineLicense, OfflineLicenseError,
+    },
+    ChronoExt,
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_issue_offline_license() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let app_key = server.create_app("app").await?;
+    let other_key = server.create_app("other").await?;
+    let expiry = Utc::now() + Duration::days(30);
+    let key = server.create_license("app", expiry, None).await?;
+
+    let not_after = Utc::now() + Duration::days(7);
+    let file = server
+        .admin()
+        .issue_offline_license(authed(
+            ROOT_KEY,
+            IssueOfflineLicenseReq {
+                license: key.clone(),
+                not_after: Some(not_after.to_protobuf()),
+                machine_id: None,
+            },
+        ))
+        .await?
+        .into_inner()
+        .license_file;
+    let file = OfflineLicense::decode(file.as_slice())?;
+
+    let payload = file.verify(&app_key, "any", Utc::now()).unwrap();
+    assert_eq!(payload.key_id, key);
+    assert_eq!(
+        payload.license.as_ref().and_then(|info| info.expiry),
+        Some(expiry.to_protobuf())
+    );
+
+    assert_eq!(
+        file.verify(&other_key, "any", Utc::now()).unwrap_err(),
+        OfflineLicenseError::InvalidSignature
+    );
+    assert_eq!(
+        file.verify(&app_key, "any", not_after + Duration::seconds(1))
+            .unwrap_err(),
+        OfflineLicenseError::Expired
+    );
+    assert_eq!(
+        file.verify(&app_key, "any", Utc::now() - Duration::days(1))
+            .unwrap_err(),
+        OfflineLicenseError::NotYetValid
+    );
+
+    // the window never outlives the license itself
+    let file = server
+        .admin()
+        .issue_offline_license(authed(
+            ROOT_KEY,
+            IssueOfflineLicenseReq {
+                license: key.clone(),
+                not_after: Some((expiry + Duration::days(365)).to_protobuf()),
+                machine_id: None,
+            },
+        ))
+        .await?
+        .into_inner()
+        .license_file;
+    let file = OfflineLicense::decode(file.as_slice())?;
+    assert_eq!(
+        file.verify(&app_key, "any", expiry + Duration::seconds(1))
+            .unwrap_err(),
+        OfflineLicenseError::Expired
+    );
+
+    server
+        .admin()
+        .revoke_license(authed(
+            ROOT_KEY,
+            RevokeLicenseReq {
+                license: key.clone(),
+                reason: "refund".to_owned(),
+            },
+        ))
+        .await?;
+    let status = server
+        .admin()
+        .issue_offline_license(authed(
+            ROOT_KEY,
+            IssueOfflineLicenseReq {
+                license: key,
+                not_after: None,
+                machine_id: None,
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offline_license_locked_to_machine() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let app_key = server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(30),
+            Policy {
+                limit_machines: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let issue = |machine: &str| {
+        let mut admin = server.admin();
+        let request = authed(
+            ROOT_KEY,
+            IssueOfflineLicenseReq {
+                license: key.clone(),
+                not_after: None,
+                machine_id: Some(machine.to_owned()),
+            },
+        );
+        async move { admin.issue_offline_license(request).await }
+    };
+
+    let file = issue("machine-a").await?.into_inner().license_file;
+    let file = OfflineLicense::decode(file.as_slice())?;
+    assert!(file.verify(&app_key, "machine-a", Utc::now()).is_ok());
+    assert_eq!(
+        file.verify(&app_key, "machine-b", Utc::now()).unwrap_err(),
+        OfflineLicenseError::WrongMachine
+    );
+
+    // offline activations take a machine slot like online ones
+    let status = issue("machine-b").await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    // a file for any machine would get around the limit
+    let status = server
+        .admin()
+        .issue_offline_license(authed(
+            ROOT_KEY,
+            IssueOfflineLicenseReq {
+                license: key.clone(),
+                not_after: None,
+                machine_id: None,
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offline_activation() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let app_key = server.create_app("app").await?;
+    let key = server
+        .create_license_with_policy(
+            "app",
+            Utc::now() + Duration::days(30),
+            Policy {
+                limit_machines: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let activate = |machine: &str, nonce: u64| {
+        let mut admin = server.admin();
+        let request = ActivationRequest {
+            key_id: key.clone(),
+            machine_id: machine.to_owned(),
+            nonce,
+        };
+        let request = authed(
+            ROOT_KEY,
+            ActivateOfflineReq {
+                request_file: request.encode_to_vec(),
+                not_after: None,
+            },
+        );
+        async move { admin.activate_offline(request).await }
+    };
+
+    let response = activate("machine-a", 42).await?.into_inner().response_file;
+    let file = OfflineLicense::decode(response.as_slice())?;
+    assert_eq!(file.nonce, 42);
+    assert!(file.verify(&app_key, "machine-a", Utc::now()).is_ok());
+
+    // the offline machine holds the only slot, online activations elsewhere are refused
+    let (_, response) = Session::open_on(&server, &key, "machine-b").await?;
+    assert_eq!(
+        response.result,
+        Some(info_response::Result::Error(
+            LicenseError::MachineLimit.into()
+        ))
+    );
+    let status = activate("machine-b", 43).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    let status = server
+        .admin()
+        .activate_offline(authed(
+            ROOT_KEY,
+            ActivateOfflineReq {
+                request_file: b"garbage".to_vec(),
+                not_after: None,
+            },
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use chrono::{Duration, Utc};
+use common::{authed, Session, ROOT_KEY};
+use prost::Message;
+use proto::{
+    admin_client::v1::{terminate_sessions_req::Target, ListSessionsReq, TerminateSessionsReq},
+    software::v1::{info_response, LicenseError, ResumptionToken, SignatureSchema},
+};
+
+mod common;
+
+fn is_ok(response: &proto::software::v1::InfoResponse) -> bool {
+    matches!(response.result, Some(info_response::Result::Ok(_)))
+}
+
+#[tokio::test]
+async fn test_concurrent_handshakes_respect_limit() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+
+    let handshakes = (0..8).map(|_| Session::open(&server, &key));
+    let results = futures::future::join_all(handshakes).await;
+
+    let mut sessions = Vec::new();
+    let mut rejected = 0;
+    for result in results {
+        let (session, response) = result?;
+        if is_ok(&response) {
+            sessions.push(session);
+        } else {
+            assert_eq!(
+                response.result,
+                Some(info_response::Result::Error(
+                    LicenseError::TooManySessions.into()
+                ))
+            );
+            rejected += 1;
+        }
+    }
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(rejected, 7);
+
+    // closing the only session frees its seat
+    drop(sessions);
+    let mut reopened = false;
+    for _ in 0..50 {
+        let (_, response) = Session::open(&server, &key).await?;
+        if is_ok(&response) {
+            reopened = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(reopened);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_goodbye_releases_seat() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let public_key = server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+
+    let (mut session, response) = Session::open(&server, &key).await?;
+    assert!(is_ok(&response));
+    session.heartbeat(2).await?;
+
+    let goodbye = session.goodbye(3).await?;
+    let data = goodbye.data.unwrap();
+    assert!(SignatureSchema::verify(
+        &data,
+        goodbye.nonce,
+        &public_key,
+        &goodbye.signature
+    ));
+    assert_eq!(goodbye.nonce, 3);
+    assert!(data.released);
+
+    // the seat is free before the stream is even dropped
+    let (_, response) = Session::open(&server, &key).await?;
+    assert!(is_ok(&response));
+    drop(session);
+
+    Ok(())
+}
+
+fn resumption_token(response: &proto::software::v1::InfoResponse) -> Vec<u8> {
+    match &response.result {
+        Some(info_response::Result::Ok(info)) => info.resumption_token.clone(),
+        _ => panic!("expected a license"),
+    }
+}
+
+#[tokio::test]
+async fn test_resumption_takes_over_seat() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let public_key = server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+
+    let (mut lost, response) = Session::open(&server, &key).await?;
+    let token = resumption_token(&response);
+    let decoded = ResumptionToken::decode(token.as_slice())?;
+    assert!(decoded.verify(&public_key).is_some());
+
+    // the old session still holds the only seat
+    let (_, response) = Session::open(&server, &key).await?;
+    assert!(!is_ok(&response));
+
+    let (mut resumed, response) = Session::resume(&server, &key, &token).await?;
+    assert!(is_ok(&response));
+    resumed.heartbeat(2).await?;
+
+    let sessions = server
+        .admin()
+        .list_sessions(authed(
+            ROOT_KEY,
+            ListSessionsReq {
+                license: Some(key.clone()),
+                app: None,
+            },
+        ))
+        .await?
+        .into_inner()
+        .sessions;
+    assert_eq!(sessions.len(), 1);
+
+    // the replaced session is told it's over, in answer to its own ping
+    let denial = lost.heartbeat(3).await?;
+    assert_eq!(denial.nonce, 3);
+    let data = denial.data.unwrap();
+    assert!(SignatureSchema::verify(
+        &data,
+        3,
+        &public_key,
+        &denial.signature
+    ));
+    assert_eq!(data.error, Some(LicenseError::SessionTerminated.into()));
+
+    // a token only works once, the new session has its own
+    let (_, response) = Session::resume(&server, &key, &token).await?;
+    assert!(!is_ok(&response));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replayed_token_gets_fresh_seat() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), Some(2))
+        .await?;
+
+    let (_lost, response) = Session::open(&server, &key).await?;
+    let token = resumption_token(&response);
+    let (mut resumed, response) = Session::resume(&server, &key, &token).await?;
+    assert!(is_ok(&response));
+    let resumed_token = resumption_token(&response);
+
+    // the taken over session is gone, so the replay takes the second seat
+    let (_replayed, response) = Session::resume(&server, &key, &token).await?;
+    assert!(is_ok(&response));
+    let heartbeat = resumed.heartbeat(2).await?;
+    let data = heartbeat.data.unwrap();
+    assert_eq!(data.error, None);
+
+    // heartbeats renew the token of the live session
+    let renewed = data.license.unwrap().resumption_token;
+    assert!(!renewed.is_empty());
+    assert_ne!(renewed, resumed_token);
+    let (_, response) = Session::resume(&server, &key, &renewed).await?;
+    assert!(is_ok(&response));
+    let denial = resumed.heartbeat(3).await?;
+    assert_eq!(
+        denial.data.unwrap().error,
+        Some(LicenseError::SessionTerminated.into())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resumption_token_bound_to_license() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    server.create_app("app").await?;
+    let expiry = Utc::now() + Duration::days(1);
+    let first = server.create_license("app", expiry, Some(1)).await?;
+    let second = server.create_license("app", expiry, Some(1)).await?;
+
+    let (_first_session, response) = Session::open(&server, &first).await?;
+    let token = resumption_token(&response);
+    let (_second_session, _) = Session::open(&server, &second).await?;
+
+    // another license's token frees nothing
+    let (_, response) = Session::resume(&server, &second, &token).await?;
+    assert!(!is_ok(&response));
+
+    let mut forged = token.clone();
+    let last = forged.len() - 1;
+    forged[last] ^= 1;
+    let (_, response) = Session::resume(&server, &first, &forged).await?;
+    assert!(!is_ok(&response));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_limit_shared_between_instances() -> eyre::Result<()> {
+    let path = std::env::temp_dir().join(format!("licguard-{}.db", uuid::Uuid::new_v4()));
+    let database_uri = format!("sqlite://{}?mode=rwc", path.display());
+
+    let first = common::TestServer::start_with_database(&database_uri).await?;
+    let second = common::TestServer::start_with_database(&database_uri).await?;
+
+    first.create_app("app").await?;
+    let key = first
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+
+    let (mut session, response) = Session::open(&first, &key).await?;
+    assert!(is_ok(&response));
+    session.heartbeat(2).await?;
+
+    let (_, response) = Session::open(&second, &key).await?;
+    assert_eq!(
+        response.result,
+        Some(info_response::Result::Error(
+            LicenseError::TooManySessions.into()
+        ))
+    );
+
+    drop((first, second));
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_instances_race_for_last_seat() -> eyre::Result<()> {
+    let path = std::env::temp_dir().join(format!("licguard-{}.db", uuid::Uuid::new_v4()));
+    let database_uri = format!("sqlite://{}?mode=rwc", path.display());
+
+    let first = common::TestServer::start_with_database(&database_uri).await?;
+    let second = common::TestServer::start_with_database(&database_uri).await?;
+
+    first.create_app("app").await?;
+    let key = first
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+
+    let handshakes = (0..8).map(|i| {
+        let server = if i % 2 == 0 { &first } else { &second };
+        Session::open(server, &key)
+    });
+    let results = futures::future::join_all(handshakes).await;
+
+    let mut sessions = Vec::new();
+    for result in results {
+        let (session, response) = result?;
+        if is_ok(&response) {
+            sessions.push(session);
+        } else {
+            assert_eq!(
+                response.result,
+                Some(info_response::Result::Error(
+                    LicenseError::TooManySessions.into()
+                ))
+            );
+        }
+    }
+    assert_eq!(sessions.len(), 1);
+
+    drop((sessions, first, second));
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_and_terminate_sessions() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let public_key = server.create_app("app").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), None)
+        .await?;
+
+    let (mut first, _) = Session::open(&server, &key).await?;
+    let (mut second, _) = Session::open(&server, &key).await?;
+    first.heartbeat(2).await?;
+    second.heartbeat(3).await?;
+
+    let list = |license: &str| {
+        let mut admin = server.admin();
+        let request = authed(
+            ROOT_KEY,
+            ListSessionsReq {
+                license: Some(license.to_owned()),
+                app: None,
+            },
+        );
+        async move { admin.list_sessions(request).await }
+    };
+
+    let sessions = list(&key).await?.into_inner().sessions;
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions.iter().all(|session| session.license == key));
+    assert!(sessions.iter().all(|session| !session.peer.is_empty()));
+
+    let terminated = server
+        .admin()
+        .terminate_sessions(authed(
+            ROOT_KEY,
+            TerminateSessionsReq {
+                target: Some(Target::Session(sessions[0].id.clone())),
+            },
+        ))
+        .await?
+        .into_inner();
+    assert_eq!(terminated.terminated, 1);
+    assert_eq!(list(&key).await?.into_inner().sessions.len(), 1);
+
+    server
+        .admin()
+        .terminate_sessions(authed(
+            ROOT_KEY,
+            TerminateSessionsReq {
+                target: Some(Target::License(key.clone())),
+            },
+        ))
+        .await?;
+    assert!(list(&key).await?.into_inner().sessions.is_empty());
+
+    for (session, nonce) in [(&mut first, 4), (&mut second, 5)] {
+        let denial = session.heartbeat(nonce).await?;
+        // answers the ping after the termination, not an earlier one
+        assert_eq!(denial.nonce, nonce);
+        let data = denial.data.unwrap();
+        assert!(SignatureSchema::verify(
+            &data,
+            nonce,
+            &public_key,
+            &denial.signature
+        ));
+        assert_eq!(data.error, Some(LicenseError::SessionTerminated.into()));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rejections_signed_for_requested_app() -> eyre::Result<()> {
+    let server = common::TestServer::start().await?;
+    let app = server.create_app("app").await?;
+    let other = server.create_app("other").await?;
+    let key = server
+        .create_license("app", Utc::now() + Duration::days(1), Some(1))
+        .await?;
+    let other_key = server
+        .create_license("other", Utc::now() + Duration::days(1), None)
+        .await?;
+    let invalid_key = |response: &proto::software::v1::InfoResponse| {
+        response.result
+            == Some(info_response::Result::Error(
+                LicenseError::InvalidKey.into(),
+            ))
+    };
+
+    let unknown = uuid::Uuid::new_v4().to_string();
+    let (_, response) = Session::open_for(&server, &unknown, &app).await?;
+    assert!(invalid_key(&response));
+    assert!(response.is_signed_error(&app));
+    assert!(!response.is_signed_error(&other));
+
+    // without the app the server has nothing to sign with
+    let (_, response) = Session::open(&server, &unknown).await?;
+    assert!(invalid_key(&response));
+    assert!(!response.is_signed_error(&app));
+
+    // another app's key is unknown to this one
+    let (_, response) = Session::open_for(&server, &other_key, &app).await?;
+    assert!(invalid_key(&response));
+    assert!(response.is_signed_error(&app));
+
+    let (_session, response) = Session::open_for(&server, &key, &app).await?;
+    assert!(is_ok(&response));
+    let (_, response) = Session::open_for(&server, &key, &app).await?;
+    assert_eq!(
+        response.result,
+        Some(info_response::Result::Error(
+            LicenseError::TooManySessions.into()
+        ))
+    );
+    assert!(response.is_signed_error(&app));
+
+    Ok(())
+}